@@ -0,0 +1,80 @@
+use crate::config::DEFAULT_CONFIG_FILE;
+use crate::MAX_PLAYERS;
+use std::env;
+use std::path::PathBuf;
+
+const DEFAULT_HEADLESS_TICKS: u64 = 60 * 60;
+
+//コマンドライン引数
+pub struct LaunchOptions {
+    //ウィンドウなしでゲームロジックだけを動かす
+    pub headless: bool,
+    //ヘッドレスモードで進める tick 数
+    pub ticks: u64,
+    //乱数のシード
+    pub seed: Option<u64>,
+    //ヘッドレスモードの人数。ウィンドウモードではタイトル画面で選ぶ
+    pub players: usize,
+    //入力を記録するリプレイファイル
+    pub record: Option<PathBuf>,
+    //再生するリプレイファイル
+    pub replay: Option<PathBuf>,
+    //設定ファイル
+    pub config: PathBuf,
+}
+
+impl Default for LaunchOptions {
+    fn default() -> Self {
+        Self {
+            headless: false,
+            ticks: DEFAULT_HEADLESS_TICKS,
+            seed: None,
+            players: 1,
+            record: None,
+            replay: None,
+            config: PathBuf::from(DEFAULT_CONFIG_FILE),
+        }
+    }
+}
+
+impl LaunchOptions {
+    pub fn from_args() -> Self {
+        Self::parse(env::args().skip(1))
+    }
+
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Self {
+        let mut options = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--headless" => options.headless = true,
+                "--ticks" => match args.next().and_then(|v| v.parse().ok()) {
+                    Some(ticks) => options.ticks = ticks,
+                    None => eprintln!("--ticks expects a number"),
+                },
+                "--seed" => match args.next().and_then(|v| v.parse().ok()) {
+                    Some(seed) => options.seed = Some(seed),
+                    None => eprintln!("--seed expects a number"),
+                },
+                "--players" => match args.next().and_then(|v| v.parse().ok()) {
+                    Some(players) if (1..=MAX_PLAYERS).contains(&players) => options.players = players,
+                    _ => eprintln!("--players expects a number from 1 to {}", MAX_PLAYERS),
+                },
+                "--record" => match args.next() {
+                    Some(path) => options.record = Some(path.into()),
+                    None => eprintln!("--record expects a file path"),
+                },
+                "--config" => match args.next() {
+                    Some(path) => options.config = path.into(),
+                    None => eprintln!("--config expects a file path"),
+                },
+                "--replay" => match args.next() {
+                    Some(path) => options.replay = Some(path.into()),
+                    None => eprintln!("--replay expects a file path"),
+                },
+                _ => eprintln!("unknown argument: {}", arg),
+            }
+        }
+        options
+    }
+}
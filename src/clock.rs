@@ -0,0 +1,247 @@
+use crate::components::{BossPart, Enemy, Movable};
+use crate::state::AppState;
+use crate::TIME_STEP;
+use bevy::ecs::schedule::{IntoSystemDescriptor, ShouldRun};
+use bevy::prelude::*;
+use bevy::transform::{transform_propagate_system, TransformSystem};
+use std::time::Duration;
+
+//描画が遅れた時に1フレームで進める時間の上限。これを超えた分はゲームが遅くなる
+const MAX_FRAME_TIME: f64 = 0.25;
+
+//ゲームロジック用の時計。Playing 中は TIME_STEP 秒ごとに1 tick 進む
+#[derive(Default)]
+pub struct SimTime {
+    tick: u64,
+    //まだ tick に使っていない経過時間 (秒)
+    accumulator: f64,
+    //このフレームで既に tick を進めたか
+    looping: bool,
+    //ゲーム開始からゲームオーバーまでの間だけ進める
+    running: bool,
+}
+
+impl SimTime {
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    pub fn seconds(&self) -> f64 {
+        self.tick as f64 * TIME_STEP as f64
+    }
+
+    pub fn delta(&self) -> Duration {
+        Duration::from_secs_f32(TIME_STEP)
+    }
+
+    pub fn delta_seconds(&self) -> f32 {
+        TIME_STEP
+    }
+
+    /// 最後の tick から次の tick までの進み具合 (0〜1)。描画の補間に使う
+    pub fn alpha(&self) -> f32 {
+        (self.accumulator / TIME_STEP as f64).clamp(0., 1.) as f32
+    }
+
+    /// ゲーム開始時に tick を 0 に戻して時計を動かす
+    pub fn reset(&mut self) {
+        self.tick = 0;
+        self.running = true;
+    }
+
+    /// ゲームオーバーになったら、同じフレームの残りの tick を進めない
+    pub fn halt(&mut self) {
+        self.running = false;
+    }
+}
+
+//1フレームで tick をどれだけ進めるか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SimPacing {
+    //経過時間を貯めて TIME_STEP ごとに進める
+    RealTime,
+    //app.update() 1回で1 tick 進める (ヘッドレス用)
+    Stepped,
+}
+
+/// 秒を一番近い tick 数にする。短い時間は 0 になるので、毎 tick より速くしたくない場合は呼び出す側で .max(1) する
+pub fn seconds_to_ticks(seconds: f64) -> u64 {
+    (seconds / TIME_STEP as f64).round() as u64
+}
+
+/// `seconds` 秒ごとに1回だけ実行するランクライテリア
+pub fn sim_every(seconds: f64) -> impl FnMut(Res<SimTime>) -> ShouldRun {
+    let ticks = seconds_to_ticks(seconds).max(1);
+    move |sim_time: Res<SimTime>| {
+        if sim_time.tick.is_multiple_of(ticks) {
+            ShouldRun::Yes
+        } else {
+            ShouldRun::No
+        }
+    }
+}
+
+//固定間隔で繰り返すゲームロジックのスケジュール。CoreStage::PreUpdate と CoreStage::Update の間で実行する
+#[derive(Debug, Clone, PartialEq, Eq, Hash, StageLabel)]
+pub struct SimSchedule;
+
+//1 tick の中のステージ。コマンドはステージの終わりに反映される
+#[derive(Debug, Clone, PartialEq, Eq, Hash, StageLabel)]
+pub enum SimStage {
+    //tick を進めて入力を読む
+    PreUpdate,
+    //ゲームロジック
+    Update,
+    //リプレイの記録など、tick の結果を見る処理
+    PostUpdate,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub struct SimLabel;
+
+/// SimSchedule にシステムを追加する
+pub trait SimAppExt {
+    fn add_sim_system<Params>(
+        &mut self,
+        stage: SimStage,
+        system: impl IntoSystemDescriptor<Params>,
+    ) -> &mut Self;
+
+    fn add_sim_system_set(&mut self, stage: SimStage, system_set: SystemSet) -> &mut Self;
+}
+
+impl SimAppExt for App {
+    fn add_sim_system<Params>(
+        &mut self,
+        stage: SimStage,
+        system: impl IntoSystemDescriptor<Params>,
+    ) -> &mut Self {
+        self.schedule.stage(SimSchedule, |schedule: &mut Schedule| {
+            schedule.add_system_to_stage(stage, system)
+        });
+        self
+    }
+
+    fn add_sim_system_set(&mut self, stage: SimStage, system_set: SystemSet) -> &mut Self {
+        self.schedule.stage(SimSchedule, |schedule: &mut Schedule| {
+            schedule.add_system_set_to_stage(stage, system_set)
+        });
+        self
+    }
+}
+
+//描画の時に直前2回の tick の位置を補間するエンティティ
+#[derive(Component)]
+pub struct Interpolated {
+    previous: Vec3,
+    current: Vec3,
+}
+
+pub struct ClockPlugin;
+
+impl Plugin for ClockPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.world.contains_resource::<SimPacing>() {
+            app.insert_resource(SimPacing::RealTime);
+        }
+
+        let schedule = Schedule::default()
+            .with_run_criteria(IntoSystem::into_system(sim_step_system))
+            .with_stage(SimStage::PreUpdate, SystemStage::parallel())
+            .with_stage(SimStage::Update, SystemStage::parallel())
+            .with_stage(SimStage::PostUpdate, SystemStage::parallel());
+
+        app.insert_resource(SimTime::default())
+            .add_stage_after(CoreStage::PreUpdate, SimSchedule, schedule)
+            .add_sim_system_set(
+                SimStage::PreUpdate,
+                SystemSet::new()
+                    .label(SimLabel)
+                    .with_system(sim_time_system)
+                    .with_system(interpolation_restore_system),
+            )
+            .add_sim_system_set(
+                SimStage::PostUpdate,
+                SystemSet::new()
+                    .with_system(transform_propagate_system)
+                    .with_system(interpolation_record_system.after(transform_propagate_system)),
+            )
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                interpolation_render_system.after(TransformSystem::TransformPropagate),
+            );
+    }
+}
+
+/// 貯めた時間が TIME_STEP を超えている間 SimSchedule を繰り返す。ポーズ中やゲームの開始前・終了後は進めない
+fn sim_step_system(
+    time: Res<Time>,
+    pacing: Res<SimPacing>,
+    state: Res<State<AppState>>,
+    mut sim_time: ResMut<SimTime>,
+) -> ShouldRun {
+    if !sim_time.running || state.current() != &AppState::Playing {
+        sim_time.looping = false;
+        return ShouldRun::No;
+    }
+
+    if !sim_time.looping {
+        sim_time.accumulator += match *pacing {
+            SimPacing::RealTime => time.delta_seconds_f64().min(MAX_FRAME_TIME),
+            SimPacing::Stepped => TIME_STEP as f64,
+        };
+    }
+    if sim_time.accumulator >= TIME_STEP as f64 {
+        sim_time.accumulator -= TIME_STEP as f64;
+        sim_time.looping = true;
+        ShouldRun::YesAndCheckAgain
+    } else {
+        sim_time.looping = false;
+        ShouldRun::No
+    }
+}
+
+fn sim_time_system(mut sim_time: ResMut<SimTime>) {
+    sim_time.tick += 1;
+}
+
+//描画用に補間した位置を、tick の終わりの位置に戻す
+fn interpolation_restore_system(mut query: Query<(&Interpolated, &mut GlobalTransform)>) {
+    for (interpolated, mut global_tf) in query.iter_mut() {
+        global_tf.translation = interpolated.current;
+    }
+}
+
+type InterpolationTargetFilter = (
+    Without<Interpolated>,
+    Or<(With<Movable>, With<Enemy>, With<BossPart>)>,
+);
+
+//tick の終わりの位置を記録する。新しく出たエンティティは補間せずにその位置から表示する
+fn interpolation_record_system(
+    mut commands: Commands,
+    mut query: Query<(&GlobalTransform, &mut Interpolated)>,
+    new_query: Query<(Entity, &GlobalTransform), InterpolationTargetFilter>,
+) {
+    for (global_tf, mut interpolated) in query.iter_mut() {
+        interpolated.previous = interpolated.current;
+        interpolated.current = global_tf.translation;
+    }
+    for (entity, global_tf) in new_query.iter() {
+        commands.entity(entity).insert(Interpolated {
+            previous: global_tf.translation,
+            current: global_tf.translation,
+        });
+    }
+}
+
+//次の tick までの進み具合に合わせて、前の tick の位置との間に表示する
+fn interpolation_render_system(
+    sim_time: Res<SimTime>,
+    mut query: Query<(&Interpolated, &mut GlobalTransform)>,
+) {
+    let alpha = sim_time.alpha();
+    for (interpolated, mut global_tf) in query.iter_mut() {
+        global_tf.translation = interpolated.previous.lerp(interpolated.current, alpha);
+    }
+}
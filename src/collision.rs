@@ -0,0 +1,509 @@
+use crate::components::{Armor, Damage, FiredBy, Health, Invulnerable, Laser, Piercing, Shield};
+use crate::clock::{SimAppExt, SimStage};
+use crate::GameSystem;
+use bevy::prelude::*;
+use bevy::transform::transform_propagate_system;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+//ブロードフェーズのグリッドの1マスの大きさ
+const CELL_SIZE: f32 = 64.;
+
+/// 当たり判定の形。大きさはエンティティのスケールを掛ける前の値
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Shape {
+    //回転しない箱 (幅, 高さ)
+    Aabb(f32, f32),
+    //円 (半径)
+    Circle(f32),
+    //エンティティと一緒に回転する箱 (幅, 高さ)
+    Obb(f32, f32),
+}
+
+#[derive(Component, Debug, Clone, Copy)]
+pub struct Collider(pub Shape);
+
+impl Collider {
+    /// スプライトの大きさの設定 (100px 単位) から回転しない箱を作る
+    pub fn sprite_box((w, h): (f32, f32)) -> Self {
+        Self(Shape::Aabb(w * 100., h * 100.))
+    }
+}
+
+//当たり判定のレイヤー
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Layer {
+    Player,
+    PlayerShot,
+    Enemy,
+    EnemyShot,
+    Pickup,
+    //どちらの味方でもない障害物
+    Hazard,
+}
+
+impl Layer {
+    pub fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// member は自分が属するレイヤー、mask は自分が当たる (ダメージを与える・取得される) 相手のレイヤー
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CollisionLayers {
+    pub member: u32,
+    pub mask: u32,
+}
+
+impl CollisionLayers {
+    pub fn new(member: Layer, mask: &[Layer]) -> Self {
+        Self {
+            member: member.bit(),
+            mask: mask.iter().fold(0, |bits, layer| bits | layer.bit()),
+        }
+    }
+
+    pub fn is(&self, layer: Layer) -> bool {
+        self.member & layer.bit() != 0
+    }
+
+    /// 自分が other に当たるか
+    pub fn hits(&self, other: &CollisionLayers) -> bool {
+        self.mask & other.member != 0
+    }
+}
+
+//2つのコライダーが重なった時に送る。a と b の順番に意味はない
+#[derive(Debug, Clone, Copy)]
+pub struct CollisionEvent {
+    pub a: Entity,
+    pub b: Entity,
+}
+
+impl CollisionEvent {
+    /// 片方が is_first、もう片方が is_second に当てはまる時に (first, second) の順で返す
+    pub fn matching(
+        &self,
+        is_first: impl Fn(Entity) -> bool,
+        is_second: impl Fn(Entity) -> bool,
+    ) -> Option<(Entity, Entity)> {
+        if is_first(self.a) && is_second(self.b) {
+            Some((self.a, self.b))
+        } else if is_first(self.b) && is_second(self.a) {
+            Some((self.b, self.a))
+        } else {
+            None
+        }
+    }
+}
+
+//source が target にダメージを与えた時に送る。killed は耐久力が 0 になった時
+#[derive(Debug, Clone, Copy)]
+pub struct DamageEvent {
+    pub target: Entity,
+    pub source: Entity,
+    pub killed: bool,
+}
+
+//当たり判定を通さずに source が target に amount のダメージを与える (ボムなど)。
+//無敵・シールド・装甲は当たった時と同じように扱い、damage_system が DamageEvent を送る
+#[derive(Debug, Clone, Copy)]
+pub struct DirectDamage {
+    pub target: Entity,
+    pub source: Entity,
+    pub amount: u32,
+}
+
+//当たり判定の検出とダメージの計算。GameSystem::Collision のシステムはこの後にイベントを読む
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub enum CollisionStep {
+    //この tick で動いた親の位置を子 (ボスのパーツ) の GlobalTransform に反映する
+    Propagate,
+    Detection,
+    Damage,
+}
+
+pub struct CollisionPlugin;
+
+impl Plugin for CollisionPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<CollisionEvent>()
+            .add_event::<DamageEvent>()
+            .add_event::<DirectDamage>()
+            .add_sim_system_set(
+                SimStage::Update,
+                SystemSet::new()
+                    .after(GameSystem::Movement)
+                    .before(GameSystem::Collision)
+                    .with_system(transform_propagate_system.label(CollisionStep::Propagate))
+                    .with_system(
+                        collision_detect_system
+                            .label(CollisionStep::Detection)
+                            .after(CollisionStep::Propagate),
+                    )
+                    .with_system(
+                        damage_system
+                            .label(CollisionStep::Damage)
+                            .after(CollisionStep::Detection),
+                    ),
+            );
+    }
+}
+
+//ワールド座標での形
+enum Bounds {
+    //中心、半分の大きさ、箱の向き (x 軸, y 軸)
+    Box { center: Vec2, half: Vec2, axes: [Vec2; 2] },
+    Circle { center: Vec2, radius: f32 },
+}
+
+impl Bounds {
+    fn new(shape: Shape, translation: Vec3, rotation: Quat, scale: Vec3) -> Self {
+        let center = translation.truncate();
+        match shape {
+            Shape::Aabb(w, h) => Bounds::Box {
+                center,
+                half: Vec2::new(w, h) * scale.truncate() / 2.,
+                axes: [Vec2::X, Vec2::Y],
+            },
+            Shape::Obb(w, h) => Bounds::Box {
+                center,
+                half: Vec2::new(w, h) * scale.truncate() / 2.,
+                axes: [(rotation * Vec3::X).truncate(), (rotation * Vec3::Y).truncate()],
+            },
+            Shape::Circle(radius) => Bounds::Circle {
+                center,
+                radius: radius * scale.x.max(scale.y),
+            },
+        }
+    }
+
+    /// 形を囲む回転しない箱の (最小, 最大)
+    fn extent(&self) -> (Vec2, Vec2) {
+        let (center, half) = match *self {
+            Bounds::Box { center, half, axes } => (
+                center,
+                axes[0].abs() * half.x + axes[1].abs() * half.y,
+            ),
+            Bounds::Circle { center, radius } => (center, Vec2::splat(radius)),
+        };
+        (center - half, center + half)
+    }
+
+    fn intersects(&self, other: &Bounds) -> bool {
+        match (self, other) {
+            (Bounds::Circle { center: a, radius: ra }, Bounds::Circle { center: b, radius: rb }) => {
+                a.distance_squared(*b) < (ra + rb) * (ra + rb)
+            }
+            (Bounds::Box { center, half, axes }, Bounds::Circle { center: point, radius })
+            | (Bounds::Circle { center: point, radius }, Bounds::Box { center, half, axes }) => {
+                //箱の座標系で一番近い点を求める
+                let local = *point - *center;
+                let x = local.dot(axes[0]).clamp(-half.x, half.x);
+                let y = local.dot(axes[1]).clamp(-half.y, half.y);
+                let closest = *center + axes[0] * x + axes[1] * y;
+                closest.distance_squared(*point) < radius * radius
+            }
+            (
+                Bounds::Box { center: ca, half: ha, axes: aa },
+                Bounds::Box { center: cb, half: hb, axes: ab },
+            ) => {
+                //分離軸判定。どちらかの箱の辺の向きで分離できなければ重なっている
+                let offset = *cb - *ca;
+                aa.iter().chain(ab.iter()).all(|axis| {
+                    let ra = ha.x * aa[0].dot(*axis).abs() + ha.y * aa[1].dot(*axis).abs();
+                    let rb = hb.x * ab[0].dot(*axis).abs() + hb.y * ab[1].dot(*axis).abs();
+                    offset.dot(*axis).abs() < ra + rb
+                })
+            }
+        }
+    }
+}
+
+fn cell(value: f32) -> i32 {
+    (value / CELL_SIZE).floor() as i32
+}
+
+type ColliderQuery<'a> = (
+    Entity,
+    &'a Collider,
+    &'a CollisionLayers,
+    &'a Transform,
+    &'a GlobalTransform,
+    Option<&'a Parent>,
+);
+
+//グリッドで近くにあるコライダーの組だけを調べて、重なっていれば CollisionEvent を送る
+fn collision_detect_system(query: Query<ColliderQuery>, mut events: EventWriter<CollisionEvent>) {
+    let colliders: Vec<(Entity, CollisionLayers, Bounds)> = query
+        .iter()
+        .map(|(entity, collider, layers, tf, global_tf, parent)| {
+            //子エンティティ (ボスのパーツ) は親の位置を含めた GlobalTransform を使う。CollisionStep::Propagate で更新済み
+            let bounds = if parent.is_some() {
+                Bounds::new(collider.0, global_tf.translation, global_tf.rotation, global_tf.scale)
+            } else {
+                Bounds::new(collider.0, tf.translation, tf.rotation, tf.scale)
+            };
+            (entity, *layers, bounds)
+        })
+        .collect();
+
+    let mut grid: HashMap<(i32, i32), Vec<usize>> = HashMap::new();
+    for (i, (_, _, bounds)) in colliders.iter().enumerate() {
+        let (min, max) = bounds.extent();
+        if !(min.is_finite() && max.is_finite()) {
+            continue;
+        }
+        for x in cell(min.x)..=cell(max.x) {
+            for y in cell(min.y)..=cell(max.y) {
+                grid.entry((x, y)).or_default().push(i);
+            }
+        }
+    }
+
+    //複数のマスにまたがる組を1回にまとめ、イベントの順番を毎回同じにする
+    let mut pairs: Vec<(usize, usize)> = Vec::new();
+    for indices in grid.values() {
+        for (n, &i) in indices.iter().enumerate() {
+            pairs.extend(indices[n + 1..].iter().map(|&j| (i, j)));
+        }
+    }
+    pairs.sort_unstable();
+    pairs.dedup();
+
+    for (i, j) in pairs {
+        let ((a, layers_a, bounds_a), (b, layers_b, bounds_b)) = (&colliders[i], &colliders[j]);
+        //どちらも相手に当たらない組は調べない
+        if !(layers_a.hits(layers_b) || layers_b.hits(layers_a)) {
+            continue;
+        }
+        if bounds_a.intersects(bounds_b) {
+            events.send(CollisionEvent { a: *a, b: *b });
+        }
+    }
+}
+
+type DamageQuery<'a> = (
+    &'a CollisionLayers,
+    Option<&'a Damage>,
+    Option<&'a mut Health>,
+    Option<&'a mut Piercing>,
+    Option<&'a mut Shield>,
+    Option<&'a Invulnerable>,
+    Option<&'a Armor>,
+    Option<&'a Laser>,
+    Option<&'a FiredBy>,
+);
+
+/// target にダメージが通るか。この tick で既に倒された相手と無敵の相手には当たらない
+fn damageable(query: &Query<DamageQuery>, target: Entity) -> Option<CollisionLayers> {
+    let (layers, _, health, _, _, invulnerable, _, _, _) = query.get(target).ok()?;
+    if health.is_some_and(|health| health.0 == 0) || invulnerable.is_some() {
+        return None;
+    }
+    Some(*layers)
+}
+
+/// target の Health を damage だけ減らして倒したかを返す。シールドで防いだ場合・装甲がある場合・Health がない場合は None
+fn apply_damage(
+    commands: &mut Commands,
+    query: &mut Query<DamageQuery>,
+    target: Entity,
+    damage: u32,
+) -> Option<bool> {
+    let (_, _, health, _, shield, _, armor, _, _) = query.get_mut(target).ok()?;
+    //シールドがあれば1回分防ぐ
+    if let Some(mut shield) = shield.filter(|shield| shield.hits > 0) {
+        shield.hits -= 1;
+        if shield.hits == 0 {
+            commands.entity(target).remove::<Shield>();
+        }
+        return None;
+    }
+    if armor.is_some() {
+        return None;
+    }
+    let mut health = health?;
+    health.0 = health.0.saturating_sub(damage);
+    Some(health.0 == 0)
+}
+
+/// 重なった組のうち、mask で当たる側が Damage を持っていれば相手の Health を減らす。
+/// 弾は当たると消え、撃ち落とされても消える。それ以外の倒れた時の処理は DamageEvent を読む側で行う。
+/// 当たり判定の後に DirectDamage のダメージを与える
+fn damage_system(
+    mut commands: Commands,
+    mut collision_events: EventReader<CollisionEvent>,
+    mut direct_events: EventReader<DirectDamage>,
+    mut damage_events: EventWriter<DamageEvent>,
+    mut query: Query<DamageQuery>,
+) {
+    let mut removed: HashSet<Entity> = HashSet::new();
+    for event in collision_events.iter() {
+        let mut remove = Vec::new();
+        for (source, target) in [(event.a, event.b), (event.b, event.a)] {
+            if removed.contains(&source) || removed.contains(&target) {
+                continue;
+            }
+            let target_layers = match damageable(&query, target) {
+                Some(layers) => layers,
+                None => continue,
+            };
+
+            let damage = match query.get_mut(source) {
+                Ok((layers, Some(damage), _, piercing, _, _, _, laser, fired_by))
+                    if layers.hits(&target_layers) =>
+                {
+                    //撃った本人には当たらない
+                    if fired_by.is_some_and(|fired_by| fired_by.0 == target) {
+                        continue;
+                    }
+                    //貫通する弾は消えずに、同じ相手に2回当たらない
+                    if let Some(mut piercing) = piercing {
+                        if !piercing.first_hit(target) {
+                            continue;
+                        }
+                    } else if laser.is_some() {
+                        remove.push(source);
+                    }
+                    damage.0
+                }
+                _ => continue,
+            };
+
+            let killed = match apply_damage(&mut commands, &mut query, target, damage) {
+                Some(killed) => killed,
+                None => continue,
+            };
+            if killed && query.get(target).is_ok_and(|(_, _, _, _, _, _, _, laser, _)| laser.is_some()) {
+                remove.push(target);
+            }
+            damage_events.send(DamageEvent { target, source, killed });
+        }
+
+        for entity in remove {
+            if removed.insert(entity) {
+                commands.entity(entity).despawn();
+            }
+        }
+    }
+
+    for event in direct_events.iter() {
+        if removed.contains(&event.target) || damageable(&query, event.target).is_none() {
+            continue;
+        }
+        if let Some(killed) = apply_damage(&mut commands, &mut query, event.target, event.amount) {
+            damage_events.send(DamageEvent {
+                target: event.target,
+                source: event.source,
+                killed,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::FRAC_PI_4;
+
+    fn at(shape: Shape, x: f32, y: f32) -> Bounds {
+        Bounds::new(shape, Vec3::new(x, y, 0.), Quat::IDENTITY, Vec3::ONE)
+    }
+
+    fn rotated(shape: Shape, x: f32, y: f32, angle: f32) -> Bounds {
+        Bounds::new(shape, Vec3::new(x, y, 0.), Quat::from_rotation_z(angle), Vec3::ONE)
+    }
+
+    fn overlap(a: &Bounds, b: &Bounds) -> bool {
+        //どちらから調べても同じ結果になる
+        assert_eq!(a.intersects(b), b.intersects(a));
+        a.intersects(b)
+    }
+
+    #[test]
+    fn aabb_overlap() {
+        let a = at(Shape::Aabb(10., 10.), 0., 0.);
+        assert!(overlap(&a, &at(Shape::Aabb(10., 10.), 9., 9.)));
+        assert!(!overlap(&a, &at(Shape::Aabb(10., 10.), 11., 0.)));
+        //辺が接しているだけの場合は当たらない
+        assert!(!overlap(&a, &at(Shape::Aabb(10., 10.), 10., 0.)));
+    }
+
+    #[test]
+    fn circle_overlap() {
+        let a = at(Shape::Circle(5.), 0., 0.);
+        assert!(overlap(&a, &at(Shape::Circle(5.), 6., 6.)));
+        assert!(!overlap(&a, &at(Shape::Circle(5.), 8., 8.)));
+    }
+
+    #[test]
+    fn box_and_circle_overlap() {
+        let a = at(Shape::Aabb(10., 10.), 0., 0.);
+        assert!(overlap(&a, &at(Shape::Circle(2.), 6., 0.)));
+        //角の近くでは箱を囲む円より内側でないと当たらない
+        assert!(!overlap(&a, &at(Shape::Circle(2.), 7., 7.)));
+        assert!(overlap(&a, &at(Shape::Circle(2.), 6., 6.)));
+        //円が箱の中にある
+        assert!(overlap(&a, &at(Shape::Circle(1.), 0., 0.)));
+    }
+
+    #[test]
+    fn obb_uses_rotation() {
+        //45度回した細長い箱は、回転しない箱なら当たる位置でも当たらない
+        let bar = rotated(Shape::Obb(40., 2.), 0., 0., FRAC_PI_4);
+        let point = at(Shape::Aabb(2., 2.), 10., -10.);
+        assert!(!overlap(&bar, &point));
+        assert!(overlap(&at(Shape::Aabb(40., 2.), 0., 0.), &at(Shape::Aabb(2., 2.), 10., 0.)));
+        //箱の向きに沿った位置では当たる
+        assert!(overlap(&bar, &at(Shape::Aabb(2., 2.), 10., 10.)));
+        assert!(overlap(&bar, &at(Shape::Circle(1.5), -10., -10.)));
+    }
+
+    #[test]
+    fn aabb_ignores_rotation() {
+        let a = rotated(Shape::Aabb(40., 2.), 0., 0., FRAC_PI_4);
+        assert!(overlap(&a, &at(Shape::Aabb(2., 2.), 15., 0.)));
+        assert!(!overlap(&a, &at(Shape::Aabb(2., 2.), 10., 10.)));
+    }
+
+    #[test]
+    fn scale_changes_size() {
+        let big = Bounds::new(Shape::Circle(5.), Vec3::ZERO, Quat::IDENTITY, Vec3::new(2., 3., 1.));
+        assert!(overlap(&big, &at(Shape::Circle(1.), 15., 0.)));
+        let wide = Bounds::new(Shape::Aabb(10., 10.), Vec3::ZERO, Quat::IDENTITY, Vec3::new(2., 1., 1.));
+        assert!(overlap(&wide, &at(Shape::Aabb(2., 2.), 10., 0.)));
+        assert!(!overlap(&wide, &at(Shape::Aabb(2., 2.), 0., 7.)));
+    }
+
+    #[test]
+    fn extent_contains_rotated_box() {
+        let (min, max) = rotated(Shape::Obb(10., 10.), 0., 0., FRAC_PI_4).extent();
+        let half_diagonal = 50f32.sqrt();
+        assert!((max.x - half_diagonal).abs() < 1e-4 && (max.y - half_diagonal).abs() < 1e-4);
+        assert!((min + max).length() < 1e-4);
+        assert_eq!(at(Shape::Circle(3.), 1., 2.).extent(), (Vec2::new(-2., -1.), Vec2::new(4., 5.)));
+    }
+
+    #[test]
+    fn layers_hit_by_mask() {
+        let shot = CollisionLayers::new(Layer::PlayerShot, &[Layer::Enemy, Layer::EnemyShot]);
+        let enemy = CollisionLayers::new(Layer::Enemy, &[Layer::Player]);
+        let player = CollisionLayers::new(Layer::Player, &[]);
+        assert!(shot.is(Layer::PlayerShot) && !shot.is(Layer::Enemy));
+        assert!(shot.hits(&enemy));
+        assert!(!enemy.hits(&shot));
+        assert!(enemy.hits(&player));
+        assert!(!player.hits(&enemy));
+        assert!(!shot.hits(&player));
+    }
+
+    #[test]
+    fn matching_orders_the_pair() {
+        let (a, b) = (Entity::from_raw(1), Entity::from_raw(2));
+        let event = CollisionEvent { a, b };
+        assert_eq!(event.matching(|e| e == b, |e| e == a), Some((b, a)));
+        assert_eq!(event.matching(|e| e == a, |e| e == b), Some((a, b)));
+        assert_eq!(event.matching(|e| e == a, |e| e == a), None);
+    }
+}
@@ -1,6 +1,6 @@
 use bevy::core::Timer;
-use bevy::prelude::Component;
-use crate::{Vec2, Vec3};
+use bevy::prelude::{Color, Component, Entity, Vec2};
+use crate::Vec3;
 
 //region --- Common Components
 #[derive(Component)]
@@ -14,34 +14,194 @@ pub struct Movable{
     pub auto_despawn:bool,
 }
 
+//プレイフィールドの外に出ないエンティティ。値はワールド座標での大きさの半分
+#[derive(Component)]
+pub struct Bounded(pub Vec2);
+
 #[derive(Component)]
 pub struct Laser;
 
+//当たった相手 (CollisionLayers の mask に含まれる相手) に与えるダメージ
 #[derive(Component)]
-pub struct SpriteSize(pub Vec2);
+pub struct Damage(pub u32);
 
-impl From<(f32,f32)> for SpriteSize {
-    fn from(val: (f32,f32)) -> Self{
-        SpriteSize(Vec2::new(val.0,val.1))
-    }
+//残りの耐久力
+#[derive(Component)]
+pub struct Health(pub u32);
 
-}
+//当たってもダメージを受けない (弾は消える)
+#[derive(Component)]
+pub struct Armor;
 //endregion
 
 //region --- Player Components
 #[derive(Component)]
 pub struct Player;
 
+//プレイヤーの番号 (0: 1P, 1: 2P)。プレイヤーとその弾に付け、倒した敵の点数をその番号のスコアに入れる
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlayerId(pub usize);
+
+//プレイヤーの武器の強さ。パワーアップで1段階ずつ上がる
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WeaponTier {
+    Single,
+    Twin,
+    //3方向に広げる
+    Triple,
+    //敵を貫通するビーム
+    Beam,
+}
+
+impl WeaponTier {
+    pub fn upgraded(self) -> Self {
+        match self {
+            WeaponTier::Single => WeaponTier::Twin,
+            WeaponTier::Twin => WeaponTier::Triple,
+            WeaponTier::Triple | WeaponTier::Beam => WeaponTier::Beam,
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct Weapon {
+    pub tier: WeaponTier,
+    //次に撃てる tick
+    pub next_fire: u64,
+}
+
+impl Default for Weapon {
+    fn default() -> Self {
+        Self {
+            tier: WeaponTier::Single,
+            next_fire: 0,
+        }
+    }
+}
+
+//敵に当たっても消えないレーザー。同じ敵には1回だけ当たる
+#[derive(Component, Default)]
+pub struct Piercing {
+    hit: Vec<Entity>,
+}
+
+impl Piercing {
+    /// 初めて当たった敵なら true
+    pub fn first_hit(&mut self, target: Entity) -> bool {
+        if self.hit.contains(&target) {
+            return false;
+        }
+        self.hit.push(target);
+        true
+    }
+}
+
+//無敵中は敵の弾がすり抜ける。点滅して表示する
+#[derive(Component)]
+pub struct Invulnerable {
+    //無敵が切れる tick
+    pub until: u64,
+}
+
+impl Invulnerable {
+    /// 今の無敵の方が長い場合はそちらを残す
+    pub fn extended(current: Option<&Invulnerable>, until: u64) -> Self {
+        Self {
+            until: current.map_or(until, |current| current.until.max(until)),
+        }
+    }
+}
+
+//敵の弾を hits 回まで防ぐシールド
 #[derive(Component)]
-pub struct FromPlayer;
+pub struct Shield {
+    pub hits: u32,
+}
+
+//スピードアップ中は移動の速さに倍率を掛ける
+#[derive(Component)]
+pub struct SpeedBoost {
+    pub multiplier: f32,
+    pub until: u64,
+}
+//endregion
+
+//region --- Pickup Components
+//パワーアップの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerUp {
+    //武器を1段階強くする
+    Weapon,
+    Shield,
+    //残機を1増やす
+    Life,
+    Speed,
+    //ボムを1つ増やす
+    Bomb,
+}
+
+//敵が落としたパワーアップ。プレイヤーが触れると取得する
+#[derive(Component)]
+pub struct Pickup(pub PowerUp);
 //endregion
 
 
 #[derive(Component)]
 pub struct Enemy;
 
+//敵の種類の名前 (EnemyTypes のキー)
+#[derive(Component)]
+pub struct EnemyKind(pub String);
+
+//撃墜時に得られる点数
+#[derive(Component)]
+pub struct Points(pub u32);
+
+//点滅が終わった時に戻す色
+#[derive(Component)]
+pub struct BaseColor(pub Color);
+
+//被弾して点滅している残り時間
 #[derive(Component)]
-pub struct FromEnemy;
+pub struct HitFlash(pub Timer);
+
+impl Default for HitFlash {
+    fn default() -> Self {
+        Self(Timer::from_seconds(0.1, false))
+    }
+}
+
+//敵の弾を撃った敵
+#[derive(Component)]
+pub struct FiredBy(pub Entity);
+
+//ボス本体。パーツは子エンティティ
+#[derive(Component)]
+pub struct Boss {
+    //BossTypes のキー
+    pub name: String,
+    pub phase: usize,
+    //破壊されたパーツの数
+    pub destroyed: u32,
+    //左右に往復する向き
+    pub direction: f32,
+}
+
+//ボスのパーツ。コアが破壊されるとボスが倒れる
+#[derive(Component)]
+pub struct BossPart {
+    pub core: bool,
+    pub points: u32,
+}
+
+//ボスを倒した時の連続爆発
+#[derive(Component)]
+pub struct ChainExplosion {
+    pub center: Vec3,
+    pub radius: f32,
+    pub remaining: u32,
+    pub timer: Timer,
+}
 
 #[derive(Component)]
 pub struct Explosion;
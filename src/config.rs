@@ -0,0 +1,613 @@
+use crate::collision::Layer;
+use bevy::asset::FileAssetIo;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+pub const DEFAULT_CONFIG_FILE: &str = "config/game.ron";
+//AssetServerSettings の既定のフォルダ
+const ASSET_FOLDER: &str = "assets";
+//設定ファイルの更新を確認する間隔
+const WATCH_INTERVAL: f32 = 0.5;
+
+//ゲームバランスの設定。起動時にファイルから読み込む
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GameConfig {
+    pub window: WindowConfig,
+    pub sprites: SpriteConfig,
+    pub player: PlayerConfig,
+    pub enemy: EnemyConfig,
+    pub pickup: PickupConfig,
+    pub hud: HudConfig,
+    pub collision: CollisionConfig,
+    pub playfield: PlayfieldConfig,
+    //Velocity 1.0 あたりの1秒間の移動量
+    pub base_speed: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WindowConfig {
+    pub title: String,
+    pub width: f32,
+    pub height: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpriteConfig {
+    pub player: String,
+    pub player_laser: String,
+    pub enemy_laser: String,
+    pub explosion_sheet: String,
+    pub scale: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PlayerConfig {
+    pub size: (f32, f32),
+    pub laser_size: (f32, f32),
+    //やられてから復活するまでの秒数
+    pub respawn_delay: f64,
+    //復活した後に無敵になる秒数
+    pub respawn_invulnerable: f64,
+    pub lives: u32,
+    //このスコアに達するたびに残機が1増える
+    pub extra_life_scores: Vec<u32>,
+    //レーザーを撃つ間隔 (秒)
+    pub fire_interval: f64,
+    //ボタンを押し続けると連射する
+    pub autofire: bool,
+    //ゲーム開始時のボムの数
+    pub bombs: u32,
+    //ボムが敵に与えるダメージ
+    pub bomb_damage: u32,
+    //ボムを使った後に無敵になる秒数
+    pub bomb_invulnerable: f64,
+    //1秒間に増やせる・減らせる Velocity (大きいほどきびきび動く)
+    pub acceleration: f32,
+    pub deceleration: f32,
+    //低速移動キーを押している間の速さの倍率
+    pub focus_multiplier: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EnemyConfig {
+    pub size: (f32, f32),
+    pub laser_size: (f32, f32),
+    //フォーメーションを周回する速さ
+    pub speed: f32,
+    //全ての敵の攻撃の頻度に掛ける倍率
+    pub fire_rate: f64,
+    //フォーメーションの楕円の半径 (最小, 最大)
+    pub formation_radius_x: (f32, f32),
+    pub formation_radius_y: (f32, f32),
+    //フォーメーションを全滅させた時のボーナス
+    pub formation_bonus: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PickupConfig {
+    //敵を倒した時にパワーアップを落とす確率
+    pub drop_chance: f64,
+    //落とすパワーアップの種類の重み
+    pub weights: PickupWeights,
+    pub size: (f32, f32),
+    //落ちる速さ (Velocity の大きさ)
+    pub fall_speed: f32,
+    //シールドで無敵になる秒数
+    pub shield_duration: f64,
+    //1以上の場合は時間で切れる無敵の代わりに、この回数まで弾を防ぐシールドになる
+    pub shield_hits: u32,
+    //スピードアップの持続時間 (秒) と速さの倍率
+    pub speed_duration: f64,
+    pub speed_multiplier: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PickupWeights {
+    pub weapon: u32,
+    pub shield: u32,
+    pub life: u32,
+    pub speed: u32,
+    pub bomb: u32,
+}
+
+//プレイヤーが動ける範囲。ウィンドウの端からの余白
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PlayfieldConfig {
+    pub margin_left: f32,
+    pub margin_right: f32,
+    pub margin_top: f32,
+    pub margin_bottom: f32,
+}
+
+//それぞれが当たる (ダメージを与える・取得される) 相手のレイヤー
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CollisionConfig {
+    pub player: Vec<Layer>,
+    pub player_shot: Vec<Layer>,
+    pub enemy: Vec<Layer>,
+    pub enemy_shot: Vec<Layer>,
+    pub pickup: Vec<Layer>,
+}
+
+//HUD の表示言語
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Language {
+    English,
+    Japanese,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HudConfig {
+    pub language: Language,
+    //日本語の表示に使うフォント (assets からのパス)
+    pub cjk_font: String,
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        Self {
+            window: WindowConfig::default(),
+            sprites: SpriteConfig::default(),
+            player: PlayerConfig::default(),
+            enemy: EnemyConfig::default(),
+            pickup: PickupConfig::default(),
+            hud: HudConfig::default(),
+            collision: CollisionConfig::default(),
+            playfield: PlayfieldConfig::default(),
+            base_speed: 500.,
+        }
+    }
+}
+
+impl Default for WindowConfig {
+    fn default() -> Self {
+        Self {
+            title: "Rust Invaders".to_string(),
+            width: 598.,
+            height: 676.,
+        }
+    }
+}
+
+impl Default for SpriteConfig {
+    fn default() -> Self {
+        Self {
+            player: "Player.png".to_string(),
+            player_laser: "laser.png".to_string(),
+            enemy_laser: "Flame_01.png".to_string(),
+            explosion_sheet: "Explosion.png".to_string(),
+            scale: 0.5,
+        }
+    }
+}
+
+impl Default for PlayerConfig {
+    fn default() -> Self {
+        Self {
+            size: (1.5, 0.78125),
+            laser_size: (9., 54.),
+            respawn_delay: 2.,
+            respawn_invulnerable: 2.,
+            lives: 3,
+            extra_life_scores: vec![1000, 3000, 6000],
+            fire_interval: 0.2,
+            autofire: true,
+            bombs: 2,
+            bomb_damage: 3,
+            bomb_invulnerable: 1.,
+            acceleration: 8.,
+            deceleration: 12.,
+            focus_multiplier: 0.4,
+        }
+    }
+}
+
+impl Default for EnemyConfig {
+    fn default() -> Self {
+        Self {
+            size: (1.5, 0.78125),
+            laser_size: (9., 54.),
+            speed: 500.,
+            fire_rate: 1.,
+            formation_radius_x: (80., 150.),
+            formation_radius_y: (100., 100.),
+            formation_bonus: 500,
+        }
+    }
+}
+
+impl Default for PickupConfig {
+    fn default() -> Self {
+        Self {
+            drop_chance: 0.1,
+            weights: PickupWeights::default(),
+            size: (24., 24.),
+            fall_speed: 0.3,
+            shield_duration: 5.,
+            shield_hits: 0,
+            speed_duration: 8.,
+            speed_multiplier: 1.5,
+        }
+    }
+}
+
+impl Default for PickupWeights {
+    fn default() -> Self {
+        Self {
+            weapon: 4,
+            shield: 2,
+            life: 1,
+            speed: 2,
+            bomb: 2,
+        }
+    }
+}
+
+impl Default for HudConfig {
+    fn default() -> Self {
+        Self {
+            language: Language::English,
+            cjk_font: "fonts/NotoSansJP-Bold.otf".to_string(),
+        }
+    }
+}
+
+impl Default for PlayfieldConfig {
+    fn default() -> Self {
+        Self {
+            margin_left: 10.,
+            margin_right: 10.,
+            margin_top: 40.,
+            margin_bottom: 40.,
+        }
+    }
+}
+
+impl Default for CollisionConfig {
+    fn default() -> Self {
+        Self {
+            player: Vec::new(),
+            player_shot: vec![Layer::Enemy],
+            enemy: Vec::new(),
+            enemy_shot: vec![Layer::Player],
+            pickup: vec![Layer::Player],
+        }
+    }
+}
+
+impl PickupWeights {
+    pub fn total(&self) -> u32 {
+        self.weapon + self.shield + self.life + self.speed + self.bomb
+    }
+}
+
+impl GameConfig {
+    /// ファイルから読み込む。ファイルがない・不正な場合はデフォルト値を使う
+    pub fn load_or_default(path: &Path) -> Self {
+        if !path.exists() {
+            return Self::default();
+        }
+        Self::load(path).unwrap_or_else(|err| {
+            eprintln!("{}; using default config", err);
+            Self::default()
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+        let config: Self = ron::from_str(&text)
+            .map_err(|err| format!("failed to parse {}: {}", path.display(), err))?;
+        config
+            .validate()
+            .map_err(|err| format!("invalid config {}: {}", path.display(), err))?;
+        Ok(config)
+    }
+
+    /// 動いている設定をこの設定で置き換えられるか。ウィンドウ (プレイフィールド) は起動時にしか変えられない
+    pub fn check_reload(&self, running: &GameConfig) -> Result<(), String> {
+        if self.window != running.window {
+            return Err("window cannot be changed while the game is running".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        fn positive(name: &str, value: f32) -> Result<(), String> {
+            if value > 0. && value.is_finite() {
+                Ok(())
+            } else {
+                Err(format!("{} must be positive (got {})", name, value))
+            }
+        }
+
+        positive("window.width", self.window.width)?;
+        positive("window.height", self.window.height)?;
+        positive("sprites.scale", self.sprites.scale)?;
+        positive("player.size.0", self.player.size.0)?;
+        positive("player.size.1", self.player.size.1)?;
+        positive("player.laser_size.0", self.player.laser_size.0)?;
+        positive("player.laser_size.1", self.player.laser_size.1)?;
+        positive("enemy.size.0", self.enemy.size.0)?;
+        positive("enemy.size.1", self.enemy.size.1)?;
+        positive("enemy.laser_size.0", self.enemy.laser_size.0)?;
+        positive("enemy.laser_size.1", self.enemy.laser_size.1)?;
+        positive("base_speed", self.base_speed)?;
+        positive("enemy.speed", self.enemy.speed)?;
+        positive("enemy.formation_radius_x.0", self.enemy.formation_radius_x.0)?;
+        positive("enemy.formation_radius_y.0", self.enemy.formation_radius_y.0)?;
+        positive("pickup.size.0", self.pickup.size.0)?;
+        positive("pickup.size.1", self.pickup.size.1)?;
+        positive("pickup.fall_speed", self.pickup.fall_speed)?;
+        positive("pickup.speed_multiplier", self.pickup.speed_multiplier)?;
+        positive("player.acceleration", self.player.acceleration)?;
+        positive("player.deceleration", self.player.deceleration)?;
+        positive("player.focus_multiplier", self.player.focus_multiplier)?;
+
+        if !(self.enemy.fire_rate > 0. && self.enemy.fire_rate.is_finite()) {
+            return Err(format!("enemy.fire_rate must be positive (got {})", self.enemy.fire_rate));
+        }
+        for (name, (min, max)) in [
+            ("enemy.formation_radius_x", self.enemy.formation_radius_x),
+            ("enemy.formation_radius_y", self.enemy.formation_radius_y),
+        ] {
+            if min > max {
+                return Err(format!("{} must be (min, max) (got ({}, {}))", name, min, max));
+            }
+        }
+
+        if self.player.respawn_delay < 0. || self.player.respawn_delay.is_nan() {
+            return Err(format!(
+                "player.respawn_delay must not be negative (got {})",
+                self.player.respawn_delay
+            ));
+        }
+        if self.player.fire_interval < 0. || self.player.fire_interval.is_nan() {
+            return Err(format!(
+                "player.fire_interval must not be negative (got {})",
+                self.player.fire_interval
+            ));
+        }
+        if !(0. ..=1.).contains(&self.pickup.drop_chance) {
+            return Err(format!(
+                "pickup.drop_chance must be between 0 and 1 (got {})",
+                self.pickup.drop_chance
+            ));
+        }
+        if self.pickup.weights.total() == 0 {
+            return Err("pickup.weights must not be all zero".to_string());
+        }
+        for (name, seconds) in [
+            ("pickup.shield_duration", self.pickup.shield_duration),
+            ("pickup.speed_duration", self.pickup.speed_duration),
+        ] {
+            if seconds < 0. || seconds.is_nan() {
+                return Err(format!("{} must not be negative (got {})", name, seconds));
+            }
+        }
+        for (name, seconds) in [
+            ("player.respawn_invulnerable", self.player.respawn_invulnerable),
+            ("player.bomb_invulnerable", self.player.bomb_invulnerable),
+        ] {
+            if seconds < 0. || seconds.is_nan() {
+                return Err(format!("{} must not be negative (got {})", name, seconds));
+            }
+        }
+        for (name, margin) in [
+            ("playfield.margin_left", self.playfield.margin_left),
+            ("playfield.margin_right", self.playfield.margin_right),
+            ("playfield.margin_top", self.playfield.margin_top),
+            ("playfield.margin_bottom", self.playfield.margin_bottom),
+        ] {
+            if margin < 0. || margin.is_nan() {
+                return Err(format!("{} must not be negative (got {})", name, margin));
+            }
+        }
+        if self.playfield.margin_left + self.playfield.margin_right >= self.window.width
+            || self.playfield.margin_top + self.playfield.margin_bottom >= self.window.height
+        {
+            return Err("playfield margins must leave room inside the window".to_string());
+        }
+        if self.player.lives == 0 {
+            return Err("player.lives must be at least 1".to_string());
+        }
+        if self.player.extra_life_scores.windows(2).any(|w| w[0] >= w[1]) {
+            return Err("player.extra_life_scores must be in ascending order".to_string());
+        }
+        //日本語の時は AssetServer と同じ assets フォルダにフォントが必要
+        if self.hud.language == Language::Japanese {
+            let font_path = FileAssetIo::get_root_path().join(ASSET_FOLDER).join(&self.hud.cjk_font);
+            if !font_path.is_file() {
+                return Err(format!("hud.cjk_font {} does not exist", font_path.display()));
+            }
+        }
+        Ok(())
+    }
+}
+
+//設定ファイルが再読み込みされた時に送る
+pub struct ConfigReloaded;
+
+//設定ファイルの更新を監視する。ウィンドウモードで、リプレイの記録・再生をしていない時だけ使う
+pub struct ConfigWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    timer: Timer,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: PathBuf) -> Self {
+        let modified = modified_time(&path);
+        Self {
+            path,
+            modified,
+            timer: Timer::from_seconds(WATCH_INTERVAL, true),
+        }
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+pub struct ConfigPlugin;
+
+impl Plugin for ConfigPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<ConfigReloaded>()
+            .add_system(config_watch_system);
+    }
+}
+
+//ファイルが更新されたら読み込み直す。不正な場合は今の設定を使い続ける
+fn config_watch_system(
+    time: Res<Time>,
+    watcher: Option<ResMut<ConfigWatcher>>,
+    mut config: ResMut<GameConfig>,
+    mut events: EventWriter<ConfigReloaded>,
+) {
+    let mut watcher = match watcher {
+        Some(watcher) => watcher,
+        None => return,
+    };
+    if !watcher.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+
+    let modified = modified_time(&watcher.path);
+    if modified.is_none() || modified == watcher.modified {
+        return;
+    }
+    watcher.modified = modified;
+
+    let result = GameConfig::load(&watcher.path).and_then(|new_config| {
+        new_config
+            .check_reload(&config)
+            .map_err(|err| format!("cannot reload {}: {}", watcher.path.display(), err))?;
+        Ok(new_config)
+    });
+    match result {
+        Ok(new_config) => {
+            *config = new_config;
+            events.send(ConfigReloaded);
+            info!("reloaded {}", watcher.path.display());
+        }
+        Err(err) => error!("{}; keeping the previous config", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //config を変えて検証し、エラーメッセージを返す
+    fn invalid(change: impl FnOnce(&mut GameConfig)) -> String {
+        let mut config = GameConfig::default();
+        change(&mut config);
+        config.validate().expect_err("config should be rejected")
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        assert_eq!(GameConfig::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn shipped_config_is_valid() {
+        GameConfig::load(Path::new(DEFAULT_CONFIG_FILE)).unwrap();
+    }
+
+    #[test]
+    fn rejects_non_positive_sizes() {
+        assert!(invalid(|config| config.window.width = 0.).contains("window.width"));
+        assert!(invalid(|config| config.window.height = -1.).contains("window.height"));
+        assert!(invalid(|config| config.sprites.scale = f32::NAN).contains("sprites.scale"));
+        assert!(invalid(|config| config.base_speed = f32::INFINITY).contains("base_speed"));
+    }
+
+    #[test]
+    fn rejects_reversed_ranges() {
+        let err = invalid(|config| config.enemy.formation_radius_x = (200., 100.));
+        assert!(err.contains("enemy.formation_radius_x"));
+        let err = invalid(|config| config.player.extra_life_scores = vec![20000, 10000]);
+        assert!(err.contains("player.extra_life_scores"));
+        let err = invalid(|config| config.player.extra_life_scores = vec![10000, 10000]);
+        assert!(err.contains("player.extra_life_scores"));
+    }
+
+    #[test]
+    fn rejects_negative_durations() {
+        assert!(invalid(|config| config.player.respawn_delay = -0.1).contains("player.respawn_delay"));
+        assert!(invalid(|config| config.pickup.shield_duration = -1.).contains("pickup.shield_duration"));
+        let err = invalid(|config| config.player.bomb_invulnerable = f64::NAN);
+        assert!(err.contains("player.bomb_invulnerable"));
+    }
+
+    #[test]
+    fn rejects_invalid_pickups() {
+        assert!(invalid(|config| config.pickup.drop_chance = 1.5).contains("pickup.drop_chance"));
+        let err = invalid(|config| {
+            config.pickup.weights = PickupWeights {
+                weapon: 0,
+                shield: 0,
+                life: 0,
+                speed: 0,
+                bomb: 0,
+            }
+        });
+        assert!(err.contains("pickup.weights"));
+    }
+
+    #[test]
+    fn rejects_margins_that_fill_the_window() {
+        let err = invalid(|config| {
+            config.playfield.margin_left = config.window.width / 2.;
+            config.playfield.margin_right = config.window.width / 2.;
+        });
+        assert!(err.contains("playfield margins"));
+        assert!(invalid(|config| config.playfield.margin_top = -1.).contains("playfield.margin_top"));
+    }
+
+    #[test]
+    fn rejects_zero_lives() {
+        assert!(invalid(|config| config.player.lives = 0).contains("player.lives"));
+    }
+
+    #[test]
+    fn reload_keeps_the_window() {
+        let running = GameConfig::default();
+        let mut new_config = running.clone();
+        new_config.enemy.speed *= 2.;
+        assert_eq!(new_config.check_reload(&running), Ok(()));
+
+        new_config.window.width += 100.;
+        assert!(new_config.check_reload(&running).unwrap_err().contains("window"));
+    }
+
+    #[test]
+    fn rejects_missing_cjk_font() {
+        let err = invalid(|config| {
+            config.hud.language = Language::Japanese;
+            config.hud.cjk_font = "fonts/does-not-exist.otf".to_string();
+        });
+        assert!(err.contains("hud.cjk_font"));
+
+        //英語の時はフォントがなくてもよい
+        let mut config = GameConfig::default();
+        config.hud.cjk_font = "fonts/does-not-exist.otf".to_string();
+        assert_eq!(config.validate(), Ok(()));
+    }
+}
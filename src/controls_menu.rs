@@ -0,0 +1,223 @@
+use crate::input::{Action, AxisDirection, Binding, Controls};
+use crate::state::{screen_text_cleanup_system, AppState, ScreenText, UiFont};
+use crate::MAX_PLAYERS;
+use bevy::prelude::*;
+
+//割り当てを変える時に、スティックをこれより大きく倒したら入力とみなす
+const CAPTURE_THRESHOLD: f32 = 0.5;
+
+const CAPTURE_AXES: [GamepadAxisType; 6] = [
+    GamepadAxisType::LeftStickX,
+    GamepadAxisType::LeftStickY,
+    GamepadAxisType::LeftZ,
+    GamepadAxisType::RightStickX,
+    GamepadAxisType::RightStickY,
+    GamepadAxisType::RightZ,
+];
+
+const SELECTED_COLOR: Color = Color::YELLOW;
+const ROW_COLOR: Color = Color::WHITE;
+
+//設定メニューで選んでいるプレイヤーと操作
+#[derive(Default)]
+struct ControlsMenu {
+    player: usize,
+    selected: usize,
+    //次に押した入力を割り当てる
+    waiting: bool,
+}
+
+//Action::ALL の何番目の操作の行か
+#[derive(Component)]
+struct ControlsRow(usize);
+
+#[derive(Component)]
+struct ControlsPrompt;
+
+#[derive(Component)]
+struct ControlsTitle;
+
+type RowTextFilter = (Without<ControlsPrompt>, Without<ControlsTitle>);
+
+pub struct ControlsMenuPlugin;
+
+impl Plugin for ControlsMenuPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ControlsMenu>()
+            .add_system_set(SystemSet::on_enter(AppState::Controls).with_system(controls_menu_enter_system))
+            .add_system_set(
+                SystemSet::on_update(AppState::Controls)
+                    .with_system(controls_menu_input_system)
+                    .with_system(controls_menu_text_system.after(controls_menu_input_system)),
+            )
+            .add_system_set(
+                SystemSet::on_exit(AppState::Controls)
+                    .with_system(screen_text_cleanup_system)
+                    .with_system(controls_save_system),
+            );
+    }
+}
+
+fn text_section(font: &UiFont, value: &str, font_size: f32, color: Color) -> TextBundle {
+    TextBundle {
+        text: Text::with_section(
+            value,
+            TextStyle {
+                font: font.0.clone(),
+                font_size,
+                color,
+            },
+            Default::default(),
+        ),
+        ..Default::default()
+    }
+}
+
+fn controls_menu_enter_system(mut commands: Commands, font: Res<UiFont>, mut menu: ResMut<ControlsMenu>) {
+    *menu = ControlsMenu::default();
+
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                size: Size::new(Val::Percent(100.), Val::Percent(100.)),
+                flex_direction: FlexDirection::ColumnReverse,
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                ..Default::default()
+            },
+            color: Color::NONE.into(),
+            ..Default::default()
+        })
+        .insert(ScreenText)
+        .with_children(|parent| {
+            parent
+                .spawn_bundle(text_section(&font, "", 48., Color::WHITE))
+                .insert(ControlsTitle);
+            for i in 0..Action::ALL.len() {
+                parent
+                    .spawn_bundle(text_section(&font, "", 18., ROW_COLOR))
+                    .insert(ControlsRow(i));
+            }
+            parent
+                .spawn_bundle(text_section(&font, "", 18., Color::GRAY))
+                .insert(ControlsPrompt);
+        });
+}
+
+/// 選んでいる操作に、次に押したキー・ボタン・スティックを割り当てる
+fn captured_binding(
+    kb: &Input<KeyCode>,
+    gamepads: &Gamepads,
+    buttons: &Input<GamepadButton>,
+    axes: &Axis<GamepadAxis>,
+) -> Option<Binding> {
+    if let Some(&key) = kb.get_just_pressed().next() {
+        return Some(Binding::Key(key));
+    }
+    if let Some(button) = buttons.get_just_pressed().next() {
+        return Some(Binding::Button(button.1));
+    }
+    for &gamepad in gamepads.iter() {
+        for axis in CAPTURE_AXES {
+            let value = axes.get(GamepadAxis(gamepad, axis)).unwrap_or(0.);
+            if value > CAPTURE_THRESHOLD {
+                return Some(Binding::Axis(axis, AxisDirection::Positive));
+            }
+            if value < -CAPTURE_THRESHOLD {
+                return Some(Binding::Axis(axis, AxisDirection::Negative));
+            }
+        }
+    }
+    None
+}
+
+//左右でプレイヤー、上下で操作を選び、Enter で割り当てを変え、Backspace でデフォルトに戻し、Esc でタイトルに戻る
+fn controls_menu_input_system(
+    kb: Res<Input<KeyCode>>,
+    gamepads: Res<Gamepads>,
+    buttons: Res<Input<GamepadButton>>,
+    axes: Res<Axis<GamepadAxis>>,
+    mut menu: ResMut<ControlsMenu>,
+    mut controls: ResMut<Controls>,
+    mut state: ResMut<State<AppState>>,
+) {
+    let action = Action::ALL[menu.selected];
+
+    if menu.waiting {
+        //Esc は割り当てずにやめる
+        if kb.just_pressed(KeyCode::Escape) {
+            menu.waiting = false;
+        } else if let Some(binding) = captured_binding(&kb, &gamepads, &buttons, &axes) {
+            controls.rebind(menu.player, action, binding);
+            menu.waiting = false;
+        }
+        return;
+    }
+
+    let pad = |button_type: GamepadButtonType| {
+        buttons
+            .get_just_pressed()
+            .any(|button| button.1 == button_type)
+    };
+    let count = Action::ALL.len();
+    if kb.just_pressed(KeyCode::Left) || pad(GamepadButtonType::DPadLeft) {
+        menu.player = (menu.player + MAX_PLAYERS - 1) % MAX_PLAYERS;
+    } else if kb.just_pressed(KeyCode::Right) || pad(GamepadButtonType::DPadRight) {
+        menu.player = (menu.player + 1) % MAX_PLAYERS;
+    } else if kb.just_pressed(KeyCode::Up) || pad(GamepadButtonType::DPadUp) {
+        menu.selected = (menu.selected + count - 1) % count;
+    } else if kb.just_pressed(KeyCode::Down) || pad(GamepadButtonType::DPadDown) {
+        menu.selected = (menu.selected + 1) % count;
+    } else if kb.just_pressed(KeyCode::Return) || pad(GamepadButtonType::South) {
+        menu.waiting = true;
+    } else if kb.just_pressed(KeyCode::Back) || pad(GamepadButtonType::West) {
+        controls.reset(menu.player, action);
+    } else if kb.just_pressed(KeyCode::Escape) || pad(GamepadButtonType::East) {
+        state.set(AppState::Title).unwrap();
+    }
+}
+
+fn controls_menu_text_system(
+    menu: Res<ControlsMenu>,
+    controls: Res<Controls>,
+    mut row_query: Query<(&ControlsRow, &mut Text), RowTextFilter>,
+    mut prompt_query: Query<&mut Text, (With<ControlsPrompt>, Without<ControlsTitle>)>,
+    mut title_query: Query<&mut Text, With<ControlsTitle>>,
+) {
+    if !menu.is_changed() && !controls.is_changed() {
+        return;
+    }
+
+    for mut text in title_query.iter_mut() {
+        text.sections[0].value = format!("Controls - {}P", menu.player + 1);
+    }
+
+    for (row, mut text) in row_query.iter_mut() {
+        let action = Action::ALL[row.0];
+        let bindings = controls
+            .bindings(menu.player, action)
+            .iter()
+            .map(|binding| binding.label())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let section = &mut text.sections[0];
+        section.value = format!("{}: {}", action.name(), bindings);
+        section.style.color = if row.0 == menu.selected {
+            SELECTED_COLOR
+        } else {
+            ROW_COLOR
+        };
+    }
+
+    for mut text in prompt_query.iter_mut() {
+        text.sections[0].value = if menu.waiting {
+            format!("Press a key or button for {} (Esc to cancel)", Action::ALL[menu.selected].name())
+        } else {
+            "Left/Right: player / Enter: rebind / Backspace: reset / Esc: back".to_string()
+        };
+    }
+}
+
+fn controls_save_system(controls: Res<Controls>) {
+    controls.save();
+}
@@ -0,0 +1,224 @@
+use crate::config::GameConfig;
+use crate::{Playfield, WinSize};
+use bevy::app::AppExit;
+use bevy::prelude::*;
+use bevy::render::camera::Camera2d;
+use bevy::window::{WindowMode, WindowMoved, WindowResized};
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+const WINDOW_SETTINGS_FILE: &str = "window_settings.ron";
+
+//プレイフィールドの外を隠す帯の色と z (ゲームのスプライトより手前)
+const LETTERBOX_COLOR: Color = Color::BLACK;
+const LETTERBOX_Z: f32 = 900.;
+//帯の大きさ。どんな縦横比のウィンドウでも画面の端まで隠れる大きさにする
+const LETTERBOX_SIZE: f32 = 100000.;
+
+//前回終了した時のウィンドウの位置・大きさ・フルスクリーン。終了時に保存する
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WindowSettings {
+    //ウィンドウの左上の位置 (物理ピクセル)。None の場合は OS に任せる
+    pub position: Option<(i32, i32)>,
+    //None の場合は設定の window.width, window.height
+    pub size: Option<(f32, f32)>,
+    pub fullscreen: bool,
+}
+
+impl WindowSettings {
+    pub fn load() -> Self {
+        match fs::read_to_string(WINDOW_SETTINGS_FILE) {
+            Ok(text) => ron::from_str(&text).unwrap_or_else(|err| {
+                warn!("failed to parse {}: {}", WINDOW_SETTINGS_FILE, err);
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+
+    pub fn save(&self) {
+        let result = ron::ser::to_string_pretty(self, Default::default())
+            .map_err(|err| err.to_string())
+            .and_then(|text| fs::write(WINDOW_SETTINGS_FILE, text).map_err(|err| err.to_string()));
+        if let Err(err) = result {
+            warn!("failed to save {}: {}", WINDOW_SETTINGS_FILE, err);
+        }
+    }
+
+    /// 保存された大きさとモードでウィンドウを作る。位置は起動後に display_setup_system で戻す
+    pub fn descriptor(&self, config: &GameConfig) -> WindowDescriptor {
+        let (width, height) = self.size.unwrap_or((config.window.width, config.window.height));
+        WindowDescriptor {
+            title: config.window.title.clone(),
+            width,
+            height,
+            mode: if self.fullscreen {
+                WindowMode::BorderlessFullscreen
+            } else {
+                WindowMode::Windowed
+            },
+            ..Default::default()
+        }
+    }
+}
+
+//ウィンドウの中でプレイフィールドが表示されている範囲。UI と同じく左上を原点にした論理ピクセル
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PlayfieldViewport {
+    pub left: f32,
+    pub top: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+//プレイフィールドの外を隠す帯
+#[derive(Component)]
+struct Letterbox;
+
+//ウィンドウの大きさの変更・カメラの拡大縮小・ウィンドウ設定の保存
+pub struct DisplayPlugin;
+
+impl Plugin for DisplayPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.world.contains_resource::<WindowSettings>() {
+            app.insert_resource(WindowSettings::load());
+        }
+        app.init_resource::<PlayfieldViewport>()
+            .add_startup_system(display_setup_system)
+            .add_system(fullscreen_toggle_system)
+            .add_system(window_settings_track_system)
+            .add_system(win_size_system)
+            .add_system(letterbox_camera_system.after(win_size_system))
+            .add_system_to_stage(CoreStage::Last, window_settings_save_system);
+    }
+}
+
+fn display_setup_system(
+    mut commands: Commands,
+    settings: Res<WindowSettings>,
+    playfield: Res<Playfield>,
+    mut windows: ResMut<Windows>,
+) {
+    //ウィンドウの位置
+    if let (Some((x, y)), Some(window)) = (settings.position, windows.get_primary_mut()) {
+        window.set_position(IVec2::new(x, y));
+    }
+
+    //プレイフィールドの上下左右に帯を置く
+    let offsets = [
+        Vec2::new(-(playfield.w + LETTERBOX_SIZE) / 2., 0.),
+        Vec2::new((playfield.w + LETTERBOX_SIZE) / 2., 0.),
+        Vec2::new(0., -(playfield.h + LETTERBOX_SIZE) / 2.),
+        Vec2::new(0., (playfield.h + LETTERBOX_SIZE) / 2.),
+    ];
+    for offset in offsets {
+        commands
+            .spawn_bundle(SpriteBundle {
+                sprite: Sprite {
+                    color: LETTERBOX_COLOR,
+                    custom_size: Some(Vec2::splat(LETTERBOX_SIZE)),
+                    ..Default::default()
+                },
+                transform: Transform::from_translation(offset.extend(LETTERBOX_Z)),
+                ..Default::default()
+            })
+            .insert(Letterbox);
+    }
+}
+
+//F11 でフルスクリーンとウィンドウを切り替える
+fn fullscreen_toggle_system(
+    kb: Res<Input<KeyCode>>,
+    mut settings: ResMut<WindowSettings>,
+    mut windows: ResMut<Windows>,
+) {
+    if !kb.just_pressed(KeyCode::F11) {
+        return;
+    }
+    if let Some(window) = windows.get_primary_mut() {
+        settings.fullscreen = window.mode() == WindowMode::Windowed;
+        window.set_mode(if settings.fullscreen {
+            WindowMode::BorderlessFullscreen
+        } else {
+            WindowMode::Windowed
+        });
+    }
+}
+
+//ウィンドウを動かした・大きさを変えた時に設定を更新する。フルスクリーン中はウィンドウに戻した時のために残しておく
+fn window_settings_track_system(
+    mut moved_events: EventReader<WindowMoved>,
+    mut resized_events: EventReader<WindowResized>,
+    windows: Res<Windows>,
+    mut settings: ResMut<WindowSettings>,
+) {
+    let window = match windows.get_primary() {
+        Some(window) => window,
+        None => return,
+    };
+    let windowed = window.mode() == WindowMode::Windowed;
+
+    for event in moved_events.iter() {
+        if event.id == window.id() && windowed {
+            settings.position = Some((event.position.x, event.position.y));
+        }
+    }
+    for event in resized_events.iter() {
+        if event.id == window.id() && windowed {
+            settings.size = Some((event.width, event.height));
+        }
+    }
+}
+
+fn win_size_system(
+    mut resized_events: EventReader<WindowResized>,
+    windows: Res<Windows>,
+    mut win_size: ResMut<WinSize>,
+) {
+    let primary = match windows.get_primary() {
+        Some(window) => window.id(),
+        None => return,
+    };
+    if let Some(event) = resized_events.iter().rev().find(|event| event.id == primary) {
+        win_size.w = event.width;
+        win_size.h = event.height;
+    }
+}
+
+/// プレイフィールド全体が収まるようにカメラを縮小する。縦横比が違う分は帯で隠れる
+fn letterbox_camera_system(
+    win_size: Res<WinSize>,
+    playfield: Res<Playfield>,
+    mut viewport: ResMut<PlayfieldViewport>,
+    mut query: Query<&mut OrthographicProjection, With<Camera2d>>,
+) {
+    if win_size.w <= 0. || win_size.h <= 0. {
+        return;
+    }
+    //カメラの行列はウィンドウの大きさが変わった時に作り直されるので、同じフレームの PostUpdate より前に変える
+    let scale = (playfield.w / win_size.w).max(playfield.h / win_size.h);
+    for mut projection in query.iter_mut() {
+        if projection.scale != scale {
+            projection.scale = scale;
+        }
+    }
+
+    //HUD をプレイフィールドに合わせて置けるように、表示されている範囲を求める
+    let (width, height) = (playfield.w / scale, playfield.h / scale);
+    let new_viewport = PlayfieldViewport {
+        left: (win_size.w - width) / 2.,
+        top: (win_size.h - height) / 2.,
+        width,
+        height,
+    };
+    if *viewport != new_viewport {
+        *viewport = new_viewport;
+    }
+}
+
+fn window_settings_save_system(mut exit_events: EventReader<AppExit>, settings: Res<WindowSettings>) {
+    if exit_events.iter().next().is_some() {
+        settings.save();
+    }
+}
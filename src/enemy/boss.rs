@@ -0,0 +1,438 @@
+use super::bullet::{self, BulletState, EnemyWeapon, FireCheck};
+use super::wave::{Difficulty, WaveDefs, WaveState};
+use crate::clock::SimTime;
+use crate::components::{
+    Armor, BaseColor, Boss, BossPart, ChainExplosion, Damage, Enemy, ExplosionToSpawn, FiredBy,
+    Health, HitFlash, Player, PlayerId,
+};
+use crate::collision::{Collider, CollisionLayers, DamageEvent, Layer};
+use crate::config::GameConfig;
+use crate::rng::GameRng;
+use crate::score::Score;
+use crate::{EnemyCount, EnemyDestroyed, GameTextures, Playfield, HIT_FLASH_COLOR};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::f32::consts::PI;
+use std::fs;
+use std::path::Path;
+
+pub const BOSS_TYPES_FILE: &str = "config/bosses.ron";
+
+//ボスのパーツの設定
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BossPartDef {
+    pub sprite: String,
+    pub color: (f32, f32, f32),
+    //sprites.scale に掛ける大きさ
+    pub scale: f32,
+    //ボス本体の中心からの位置
+    pub offset: (f32, f32),
+    pub size: (f32, f32),
+    pub health: u32,
+    pub points: u32,
+    pub weapon: EnemyWeapon,
+    #[serde(skip)]
+    pub texture: Handle<Image>,
+}
+
+impl Default for BossPartDef {
+    fn default() -> Self {
+        Self {
+            sprite: "Enemy.png".to_string(),
+            color: (1., 1., 1.),
+            scale: 0.5,
+            offset: (0., 0.),
+            size: (1.5, 0.78125),
+            health: 5,
+            points: 300,
+            weapon: EnemyWeapon::default(),
+            texture: Handle::default(),
+        }
+    }
+}
+
+//破壊されたパーツの数で切り替わるフェーズ
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BossPhaseDef {
+    pub parts_destroyed: u32,
+    //移動の速さの倍率
+    pub speed: f32,
+    //レーザーを撃つ頻度の倍率
+    pub fire_rate: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BossDef {
+    pub core: BossPartDef,
+    #[serde(default)]
+    pub parts: Vec<BossPartDef>,
+    //左右に往復する速さ
+    pub speed: f32,
+    //画面上端から止まる位置までの距離
+    pub entry_depth: f32,
+    //このパーツ数を破壊するまでコアにダメージが通らない
+    #[serde(default)]
+    pub core_exposed_after: u32,
+    pub phases: Vec<BossPhaseDef>,
+    //倒した時の爆発の数と範囲
+    pub explosions: u32,
+    pub explosion_radius: f32,
+}
+
+impl BossDef {
+    fn phase_for(&self, destroyed: u32) -> usize {
+        self.phases
+            .iter()
+            .rposition(|phase| phase.parts_destroyed <= destroyed)
+            .unwrap_or(0)
+    }
+
+    fn parts(&self) -> impl Iterator<Item = (bool, &BossPartDef)> {
+        std::iter::once((true, &self.core)).chain(self.parts.iter().map(|part| (false, part)))
+    }
+}
+
+//ボスの種類の一覧。ウェーブの定義から名前で参照する
+#[derive(Default)]
+pub struct BossTypes(HashMap<String, BossDef>);
+
+impl BossTypes {
+    /// ファイルがない場合はボスなしで動く
+    pub fn load_or_default(path: &Path) -> Self {
+        if !path.exists() {
+            return Self::default();
+        }
+        let result = fs::read_to_string(path)
+            .map_err(|err| format!("failed to read {}: {}", path.display(), err))
+            .and_then(|text| {
+                ron::from_str::<HashMap<String, BossDef>>(&text)
+                    .map_err(|err| format!("failed to parse {}: {}", path.display(), err))
+            })
+            .map(Self)
+            .and_then(|bosses| {
+                bosses
+                    .validate()
+                    .map(|_| bosses)
+                    .map_err(|err| format!("invalid bosses {}: {}", path.display(), err))
+            });
+        result.unwrap_or_else(|err| {
+            eprintln!("{}; bosses are disabled", err);
+            Self::default()
+        })
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, boss) in &self.0 {
+            if boss.parts().any(|(_, part)| part.health == 0) {
+                return Err(format!("{}: parts need at least 1 health", name));
+            }
+            for (_, part) in boss.parts() {
+                part.weapon.validate().map_err(|err| format!("{}: {}", name, err))?;
+            }
+            if boss.core_exposed_after as usize > boss.parts.len() {
+                return Err(format!("{}: core_exposed_after exceeds the number of parts", name));
+            }
+            if boss.phases.is_empty() || boss.phases[0].parts_destroyed != 0 {
+                return Err(format!("{}: the first phase must start at parts_destroyed 0", name));
+            }
+            if boss.phases.windows(2).any(|w| w[0].parts_destroyed >= w[1].parts_destroyed) {
+                return Err(format!("{}: phases must be in ascending order", name));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&BossDef> {
+        self.0.get(name)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.contains_key(name)
+    }
+
+    pub fn load_textures(&mut self, asset_server: &AssetServer) {
+        for boss in self.0.values_mut() {
+            boss.core.texture = asset_server.load(boss.core.sprite.as_str());
+            for part in boss.parts.iter_mut() {
+                part.texture = asset_server.load(part.sprite.as_str());
+            }
+        }
+    }
+}
+
+//ボスのウェーブが始まったら出現させる
+#[allow(clippy::too_many_arguments)]
+pub fn boss_spawn_system(
+    mut commands: Commands,
+    sim_time: Res<SimTime>,
+    config: Res<GameConfig>,
+    bosses: Res<BossTypes>,
+    wave_defs: Res<WaveDefs>,
+    difficulty: Res<Difficulty>,
+    playfield: Res<Playfield>,
+    mut rng: ResMut<GameRng>,
+    mut wave_state: ResMut<WaveState>,
+    mut enemy_count: ResMut<EnemyCount>,
+) {
+    let name = match wave_state.take_boss(&wave_defs) {
+        Some(name) => name,
+        None => return,
+    };
+    let boss = match bosses.get(&name) {
+        Some(boss) => boss,
+        None => return,
+    };
+
+    let now = sim_time.tick();
+    let fire_rate = config.enemy.fire_rate * difficulty.fire_rate;
+    commands
+        .spawn()
+        .insert(Transform::from_xyz(0., playfield.h / 2. + 150., 10.))
+        .insert(GlobalTransform::default())
+        .insert(Boss {
+            name,
+            phase: 0,
+            destroyed: 0,
+            direction: 1.,
+        })
+        .with_children(|parent| {
+            for (core, part) in boss.parts() {
+                let scale = config.sprites.scale * part.scale;
+                let color = Color::rgb(part.color.0, part.color.1, part.color.2);
+                let mut part_commands = parent.spawn_bundle(SpriteBundle {
+                    texture: part.texture.clone(),
+                    sprite: Sprite {
+                        color,
+                        ..Default::default()
+                    },
+                    transform: Transform {
+                        translation: Vec3::new(part.offset.0, part.offset.1, if core { 0. } else { 1. }),
+                        scale: Vec3::new(scale, scale, 1.),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                });
+                part_commands
+                    .insert(BossPart {
+                        core,
+                        points: part.points,
+                    })
+                    .insert(part.weapon)
+                    .insert(BulletState::new(&part.weapon, now, fire_rate, &mut *rng))
+                    .insert(Collider::sprite_box(part.size))
+                    .insert(CollisionLayers::new(Layer::Enemy, &config.collision.enemy))
+                    .insert(Health(part.health))
+                    .insert(Damage(1))
+                    .insert(BaseColor(color));
+                //他のパーツが残っている間はコアにダメージが通らない
+                if core && boss.core_exposed_after > 0 {
+                    part_commands.insert(Armor);
+                }
+            }
+        });
+
+    //コアを倒すまでウェーブをクリアさせない
+    enemy_count.0 += 1;
+}
+
+//画面上部まで降りてきてから、左右に往復する
+pub fn boss_movement_system(
+    sim_time: Res<SimTime>,
+    bosses: Res<BossTypes>,
+    difficulty: Res<Difficulty>,
+    playfield: Res<Playfield>,
+    mut query: Query<(&mut Transform, &mut Boss)>,
+) {
+    for (mut transform, mut boss) in query.iter_mut() {
+        let def = match bosses.get(&boss.name) {
+            Some(def) => def,
+            None => continue,
+        };
+        let speed = def.speed * def.phases[boss.phase].speed * difficulty.speed * sim_time.delta_seconds();
+        let translation = &mut transform.translation;
+
+        let stop_y = playfield.h / 2. - def.entry_depth;
+        if translation.y > stop_y {
+            translation.y = (translation.y - speed).max(stop_y);
+            continue;
+        }
+
+        let limit = playfield.w / 2. - 100.;
+        translation.x += boss.direction * speed;
+        if translation.x.abs() > limit {
+            translation.x = translation.x.clamp(-limit, limit);
+            boss.direction = -boss.direction;
+        }
+    }
+}
+
+type BossPartFireQuery<'a> = (
+    Entity,
+    &'a Parent,
+    &'a GlobalTransform,
+    &'a EnemyWeapon,
+    &'a mut BulletState,
+);
+
+//パーツごとの間隔で撃つ。入場中は撃たない
+#[allow(clippy::too_many_arguments)]
+pub fn boss_fire_system(
+    mut commands: Commands,
+    sim_time: Res<SimTime>,
+    config: Res<GameConfig>,
+    difficulty: Res<Difficulty>,
+    game_textures: Res<GameTextures>,
+    bosses: Res<BossTypes>,
+    playfield: Res<Playfield>,
+    mut rng: ResMut<GameRng>,
+    boss_query: Query<(&Boss, &Transform)>,
+    player_query: Query<&Transform, With<Player>>,
+    enemy_query: Query<(Entity, &Transform), With<Enemy>>,
+    bullet_query: Query<&FiredBy>,
+    mut part_query: Query<BossPartFireQuery>,
+) {
+    let now = sim_time.tick();
+    let players: Vec<Vec3> = player_query.iter().map(|tf| tf.translation).collect();
+    let live_bullets = bullet::count_live_bullets(bullet_query.iter());
+    let allies: Vec<(Entity, Vec3)> = enemy_query.iter().map(|(entity, tf)| (entity, tf.translation)).collect();
+
+    for (entity, parent, global_tf, weapon, mut state) in part_query.iter_mut() {
+        let (boss, boss_tf) = match boss_query.get(parent.0) {
+            Ok(boss) => boss,
+            Err(_) => continue,
+        };
+        let def = match bosses.get(&boss.name) {
+            Some(def) => def,
+            None => continue,
+        };
+        if boss_tf.translation.y > playfield.h / 2. - def.entry_depth {
+            continue;
+        }
+
+        let rate = config.enemy.fire_rate * difficulty.fire_rate * def.phases[boss.phase].fire_rate;
+        let check = FireCheck {
+            shooter: entity,
+            origin: global_tf.translation,
+            target: bullet::nearest_player(&players, global_tf.translation),
+            live_bullets: &live_bullets,
+            allies: &allies,
+        };
+        bullet::try_fire(
+            &mut commands,
+            &config,
+            &game_textures,
+            &mut *rng,
+            now,
+            rate,
+            &check,
+            weapon,
+            &mut state,
+        );
+    }
+}
+
+type BossPartHitQuery<'a> = (&'a Parent, &'a GlobalTransform, &'a BossPart, &'a mut Sprite);
+
+//ボスのパーツがダメージを受けた時の処理
+#[allow(clippy::too_many_arguments)]
+pub fn boss_damage_system(
+    mut commands: Commands,
+    bosses: Res<BossTypes>,
+    mut score: ResMut<Score>,
+    mut enemy_count: ResMut<EnemyCount>,
+    mut damage_events: EventReader<DamageEvent>,
+    mut destroyed_events: EventWriter<EnemyDestroyed>,
+    mut boss_query: Query<(Entity, &GlobalTransform, &mut Boss, &Children)>,
+    source_query: Query<&PlayerId>,
+    core_query: Query<&BossPart>,
+    mut part_query: Query<BossPartHitQuery>,
+) {
+    let mut despawned_entities: HashSet<Entity> = HashSet::new();
+    for event in damage_events.iter() {
+        let (parent, part_tf, part, mut sprite) = match part_query.get_mut(event.target) {
+            Ok(part) => part,
+            Err(_) => continue,
+        };
+        //コアと同じ tick で倒されたパーツはボスごと削除済み
+        if despawned_entities.contains(&parent.0) {
+            continue;
+        }
+
+        if !event.killed {
+            sprite.color = HIT_FLASH_COLOR;
+            commands.entity(event.target).insert(HitFlash::default());
+            continue;
+        }
+
+        let (boss_entity, boss_tf, mut boss, children) = match boss_query.get_mut(parent.0) {
+            Ok(boss) => boss,
+            Err(_) => continue,
+        };
+        let def = match bosses.get(&boss.name) {
+            Some(def) => def,
+            None => continue,
+        };
+
+        if let Ok(&PlayerId(player)) = source_query.get(event.source) {
+            score.0[player] += part.points;
+        }
+        destroyed_events.send(EnemyDestroyed(part_tf.translation));
+        if part.core {
+            //コアが壊れたらボスごと削除して連続爆発させる
+            commands.entity(boss_entity).despawn_recursive();
+            despawned_entities.insert(boss_entity);
+            enemy_count.0 -= 1;
+            commands.spawn().insert(ChainExplosion {
+                center: boss_tf.translation,
+                radius: def.explosion_radius,
+                remaining: def.explosions,
+                timer: Timer::from_seconds(0.1, true),
+            });
+        } else {
+            commands.entity(event.target).despawn();
+            commands.spawn().insert(ExplosionToSpawn(part_tf.translation));
+
+            boss.destroyed += 1;
+            let phase = def.phase_for(boss.destroyed);
+            if phase != boss.phase {
+                boss.phase = phase;
+                info!("boss {} phase {}", boss.name, phase + 1);
+            }
+
+            //十分な数のパーツが壊れたらコアにダメージが通るようにする
+            if boss.destroyed >= def.core_exposed_after {
+                for &child in children.iter() {
+                    if core_query.get(child).is_ok_and(|part| part.core) {
+                        commands.entity(child).remove::<Armor>();
+                    }
+                }
+            }
+        }
+    }
+}
+
+//爆発を中心の周りに少しずつずらして順番に出す
+pub fn chain_explosion_system(
+    mut commands: Commands,
+    sim_time: Res<SimTime>,
+    mut query: Query<(Entity, &mut ChainExplosion)>,
+) {
+    for (entity, mut chain) in query.iter_mut() {
+        if !chain.timer.tick(sim_time.delta()).just_finished() {
+            continue;
+        }
+        if chain.remaining == 0 {
+            commands.entity(entity).despawn();
+            continue;
+        }
+        chain.remaining -= 1;
+
+        //黄金角ずつ回して偏りなく散らす
+        let angle = chain.remaining as f32 * PI * (3. - 5f32.sqrt());
+        let distance = chain.radius * ((chain.remaining % 4) as f32 + 1.) / 4.;
+        let offset = Vec3::new(angle.cos() * distance, angle.sin() * distance, 0.);
+        commands.spawn().insert(ExplosionToSpawn(chain.center + offset));
+    }
+}
@@ -0,0 +1,306 @@
+use crate::clock::{seconds_to_ticks, SimTime};
+use crate::collision::{Collider, CollisionLayers, Layer, Shape};
+use crate::components::{Damage, FiredBy, Health, Laser, Movable, Player, Velocity};
+use crate::config::GameConfig;
+use crate::GameTextures;
+use bevy::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::f32::consts::{PI, TAU};
+
+//射線をふさぐとみなす、味方と射線の距離
+const LINE_OF_SIGHT_WIDTH: f32 = 30.;
+
+//撃ち出す弾の並び方。角度は度で指定する
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum BulletPattern {
+    None,
+    //真下に spacing 間隔で平行に並べる
+    Straight { count: u32, spacing: f32 },
+    //プレイヤーを狙って spread の範囲に広げる
+    Aimed { count: u32, spread: f32 },
+    //真下を中心に spread の範囲に広げる
+    Spread { count: u32, spread: f32 },
+    //全方向に等間隔。撃つたびに step ずつ回転する
+    Spiral { count: u32, step: f32 },
+    //全方向に等間隔
+    Ring { count: u32 },
+}
+
+//敵の攻撃方法。敵の種類・ボスのパーツごとに設定する
+#[derive(Debug, Clone, Copy, PartialEq, Component, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EnemyWeapon {
+    pub pattern: BulletPattern,
+    //弾の速さ (Velocity の大きさ)
+    pub speed: f32,
+    //1回の攻撃で続けて撃つ回数と間隔 (秒)
+    pub burst: u32,
+    pub burst_delay: f64,
+    //攻撃の間隔 (秒) と、そのばらつきの割合 (0.5 なら ±50%)
+    pub interval: f64,
+    pub jitter: f64,
+    //この敵が撃った弾が画面に残っている時は撃たない
+    pub max_bullets: u32,
+    //プレイヤーがこの距離より遠い時は撃たない
+    pub range: Option<f32>,
+    //味方が射線をふさいでいる時は撃たない
+    pub line_of_sight: bool,
+}
+
+impl Default for EnemyWeapon {
+    fn default() -> Self {
+        Self {
+            pattern: BulletPattern::Straight {
+                count: 1,
+                spacing: 0.,
+            },
+            speed: 1.,
+            burst: 1,
+            burst_delay: 0.1,
+            interval: 1.,
+            jitter: 0.5,
+            max_bullets: 3,
+            range: None,
+            line_of_sight: false,
+        }
+    }
+}
+
+impl EnemyWeapon {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.interval <= 0. || self.interval.is_nan() {
+            return Err(format!("weapon.interval must be positive (got {})", self.interval));
+        }
+        if !(0. ..1.).contains(&self.jitter) {
+            return Err(format!("weapon.jitter must be between 0 and 1 (got {})", self.jitter));
+        }
+        if self.burst == 0 {
+            return Err("weapon.burst must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+//撃つ側の状態。次に撃つ tick、回転の角度と、残りの連射
+#[derive(Component, Default)]
+pub struct BulletState {
+    next_fire: u64,
+    angle: f32,
+    burst_remaining: u32,
+    next_burst: u64,
+}
+
+impl BulletState {
+    /// 出現直後から一斉に撃たないように、最初の攻撃もばらつかせる
+    pub fn new(weapon: &EnemyWeapon, now: u64, rate: f64, rng: &mut impl Rng) -> Self {
+        let mut state = Self::default();
+        state.cool_down(weapon, now, rate, rng);
+        state
+    }
+
+    //次の攻撃までの時間を決める。rate が大きいほど間隔が短い
+    fn cool_down(&mut self, weapon: &EnemyWeapon, now: u64, rate: f64, rng: &mut impl Rng) {
+        let jitter = if weapon.jitter > 0. {
+            rng.gen_range(-weapon.jitter..weapon.jitter)
+        } else {
+            0.
+        };
+        self.next_fire = now + seconds_to_ticks(weapon.interval * (1. + jitter) / rate).max(1);
+    }
+}
+
+/// origin から一番近いプレイヤーの位置。2人で遊んでいる時は近い方を狙う
+pub fn nearest_player(players: &[Vec3], origin: Vec3) -> Option<Vec3> {
+    players
+        .iter()
+        .copied()
+        .min_by(|a, b| a.distance_squared(origin).total_cmp(&b.distance_squared(origin)))
+}
+
+//撃つ側の位置と周りの状況
+pub struct FireCheck<'a> {
+    pub shooter: Entity,
+    pub origin: Vec3,
+    pub target: Option<Vec3>,
+    //撃った弾のうち画面に残っている数
+    pub live_bullets: &'a HashMap<Entity, u32>,
+    //射線をふさぐ味方の位置
+    pub allies: &'a [(Entity, Vec3)],
+}
+
+impl FireCheck<'_> {
+    fn allows(&self, weapon: &EnemyWeapon) -> bool {
+        if self.live_bullets.get(&self.shooter).copied().unwrap_or(0) >= weapon.max_bullets {
+            return false;
+        }
+        let (origin, target) = (self.origin.truncate(), self.target.map(|t| t.truncate()));
+        if let Some(range) = weapon.range {
+            match target {
+                Some(target) if origin.distance(target) <= range => {}
+                _ => return false,
+            }
+        }
+        if weapon.line_of_sight {
+            //プレイヤーがいない時は真下に向かって判定する
+            let target = target.unwrap_or(origin - Vec2::Y * 1000.);
+            let blocked = self
+                .allies
+                .iter()
+                .filter(|(entity, _)| *entity != self.shooter)
+                .any(|(_, ally)| distance_to_segment(ally.truncate(), origin, target) < LINE_OF_SIGHT_WIDTH);
+            if blocked {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+fn distance_to_segment(point: Vec2, start: Vec2, end: Vec2) -> f32 {
+    let line = end - start;
+    let t = ((point - start).dot(line) / line.length_squared().max(f32::EPSILON)).clamp(0., 1.);
+    point.distance(start + line * t)
+}
+
+/// 撃った弾の数を撃った敵ごとに数える
+pub fn count_live_bullets<'a>(fired_by: impl Iterator<Item = &'a FiredBy>) -> HashMap<Entity, u32> {
+    let mut counts = HashMap::new();
+    for fired_by in fired_by {
+        *counts.entry(fired_by.0).or_insert(0) += 1;
+    }
+    counts
+}
+
+//等間隔に広げた角度 (中心が 0)
+fn fan(count: u32, spread: f32) -> impl Iterator<Item = f32> {
+    let step = if count > 1 { spread / (count - 1) as f32 } else { 0. };
+    (0..count).map(move |i| (i as f32 - (count as f32 - 1.) / 2.) * step)
+}
+
+//全方向に等間隔の角度
+fn around(count: u32, offset: f32) -> impl Iterator<Item = f32> {
+    (0..count).map(move |i| offset + TAU * i as f32 / count as f32)
+}
+
+/// 弾の (発射位置のずれ, 進む向き) の一覧
+fn shots(
+    pattern: &BulletPattern,
+    origin: Vec3,
+    target: Option<Vec3>,
+    state: &mut BulletState,
+) -> Vec<(Vec2, f32)> {
+    let down = -PI / 2.;
+    match *pattern {
+        BulletPattern::None => Vec::new(),
+        BulletPattern::Straight { count, spacing } => fan(count, spacing * count.saturating_sub(1) as f32)
+            .map(|x| (Vec2::new(x, 0.), down))
+            .collect(),
+        BulletPattern::Aimed { count, spread } => {
+            //プレイヤーがいない時は真下を狙う
+            let aim = target
+                .map(|target| (target.y - origin.y).atan2(target.x - origin.x))
+                .unwrap_or(down);
+            fan(count, spread.to_radians()).map(|a| (Vec2::ZERO, aim + a)).collect()
+        }
+        BulletPattern::Spread { count, spread } => {
+            fan(count, spread.to_radians()).map(|a| (Vec2::ZERO, down + a)).collect()
+        }
+        BulletPattern::Spiral { count, step } => {
+            let angles = around(count, down + state.angle).map(|a| (Vec2::ZERO, a)).collect();
+            state.angle = (state.angle + step.to_radians()) % TAU;
+            angles
+        }
+        BulletPattern::Ring { count } => around(count, down).map(|a| (Vec2::ZERO, a)).collect(),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn spawn_volley(
+    commands: &mut Commands,
+    config: &GameConfig,
+    game_textures: &GameTextures,
+    shooter: Entity,
+    origin: Vec3,
+    target: Option<Vec3>,
+    weapon: &EnemyWeapon,
+    state: &mut BulletState,
+) {
+    for (offset, angle) in shots(&weapon.pattern, origin, target, state) {
+        let direction = Vec2::new(angle.cos(), angle.sin());
+        //少し前に出した位置から撃つ
+        let position = origin.truncate() + offset + direction * 15.;
+        commands
+            .spawn_bundle(SpriteBundle {
+                texture: game_textures.enemy_laser.clone(),
+                transform: Transform {
+                    translation: position.extend(0.),
+                    //スプライトは上向きなので、進む向きに回転させる
+                    rotation: Quat::from_rotation_z(angle - PI / 2.),
+                    scale: Vec3::new(config.sprites.scale, config.sprites.scale, 1.),
+                },
+                ..Default::default()
+            })
+            .insert(Laser)
+            .insert(Collider(Shape::Obb(config.enemy.laser_size.0, config.enemy.laser_size.1)))
+            .insert(CollisionLayers::new(Layer::EnemyShot, &config.collision.enemy_shot))
+            .insert(Damage(1))
+            .insert(Health(1))
+            .insert(FiredBy(shooter))
+            .insert(Movable { auto_despawn: true })
+            .insert(Velocity {
+                x: direction.x * weapon.speed,
+                y: direction.y * weapon.speed,
+            });
+    }
+}
+
+/// 攻撃の間隔が過ぎて条件を満たしていれば撃つ。連射の2発目以降は bullet_burst_system が撃つ
+#[allow(clippy::too_many_arguments)]
+pub fn try_fire(
+    commands: &mut Commands,
+    config: &GameConfig,
+    game_textures: &GameTextures,
+    rng: &mut impl Rng,
+    now: u64,
+    rate: f64,
+    check: &FireCheck,
+    weapon: &EnemyWeapon,
+    state: &mut BulletState,
+) {
+    //連射中は新しく撃ち始めない
+    if now < state.next_fire || state.burst_remaining > 0 {
+        return;
+    }
+    //条件を満たさない時は次の tick にもう一度判定する
+    if !check.allows(weapon) {
+        return;
+    }
+    spawn_volley(commands, config, game_textures, check.shooter, check.origin, check.target, weapon, state);
+    state.burst_remaining = weapon.burst.saturating_sub(1);
+    state.next_burst = now + seconds_to_ticks(weapon.burst_delay).max(1);
+    state.cool_down(weapon, now, rate, rng);
+}
+
+//連射の続きを撃つ
+pub fn bullet_burst_system(
+    mut commands: Commands,
+    sim_time: Res<SimTime>,
+    config: Res<GameConfig>,
+    game_textures: Res<GameTextures>,
+    player_query: Query<&Transform, With<Player>>,
+    mut query: Query<(Entity, &GlobalTransform, &EnemyWeapon, &mut BulletState)>,
+) {
+    let now = sim_time.tick();
+    let players: Vec<Vec3> = player_query.iter().map(|tf| tf.translation).collect();
+    for (entity, tf, weapon, mut state) in query.iter_mut() {
+        if state.burst_remaining == 0 || now < state.next_burst {
+            continue;
+        }
+        let target = nearest_player(&players, tf.translation);
+        spawn_volley(&mut commands, &config, &game_textures, entity, tf.translation, target, weapon, &mut state);
+        state.burst_remaining -= 1;
+        state.next_burst = now + seconds_to_ticks(weapon.burst_delay).max(1);
+    }
+}
@@ -1,10 +1,14 @@
 use bevy::prelude::Component;
-use rand::{Rng, thread_rng};
-use crate::{BASE_SPEED, FORMATION_MEMBERS_MAX, WinSize};
+use rand::Rng;
+use crate::config::GameConfig;
+use crate::Playfield;
 
 //敵のポジション
 #[derive(Clone, Component)]
 pub struct Formation {
+    pub id: u32,
+    //フォーメーションの敵の数
+    pub members: u32,
     pub start: (f32, f32),
     pub radius: (f32, f32),
     pub pivot: (f32, f32),
@@ -16,44 +20,70 @@ pub struct Formation {
 pub struct FormationMaker {
     current_template: Option<Formation>,
     current_members: u32,
+    next_id: u32,
+}
+
+//最小と最大が同じ場合は乱数を使わない
+fn gen_range_or_min(rng: &mut impl Rng, (min, max): (f32, f32)) -> f32 {
+    if min < max {
+        rng.gen_range(min..max)
+    } else {
+        min
+    }
 }
 
 //フォーメーション作成
 impl FormationMaker {
-    pub fn make(&mut self, win_size: &WinSize) -> Formation {
-        match (&self.current_template, self.current_members >= FORMATION_MEMBERS_MAX) {
+    //次の敵から新しいフォーメーションにする
+    pub fn reset(&mut self) {
+        self.current_template = None;
+        self.current_members = 0;
+    }
+
+    pub fn make(
+        &mut self,
+        playfield: &Playfield,
+        config: &GameConfig,
+        members: u32,
+        speed: f32,
+        rng: &mut impl Rng,
+    ) -> Formation {
+        match (&self.current_template, self.current_members >= members) {
             //敵の数が最大である場合
             (Some(tmpl), false) => {
                 self.current_members += 1;
-                tmpl.clone()
+                Formation {
+                    speed,
+                    ..tmpl.clone()
+                }
             }
             //新しいフォーメーションの作成
             (None, _) | (_, true) => {
-                let mut rng = thread_rng();
-
                 // スタート位置
-                let w_span = win_size.w / 2. + 100.;
-                let h_span = win_size.h / 2. + 100.;
+                let w_span = playfield.w / 2. + 100.;
+                let h_span = playfield.h / 2. + 100.;
                 let x = if rng.gen_bool(0.5) { w_span } else { -w_span };
-                let y = rng.gen_range(-h_span..h_span) as f32;
+                let y = rng.gen_range(-h_span..h_span);
                 let start = (x, y);
 
-                // ピボットの位置
-                let w_span = win_size.w / 4.;
-                let h_span = win_size.h / 3. - 50.;
-                let pivot = (rng.gen_range(-w_span..w_span), rng.gen_range(0.0..h_span));
+                // ピボットの位置。プレイフィールドが低すぎて範囲がない場合は中央の高さにする
+                let w_span = playfield.w / 4.;
+                let h_span = (playfield.h / 3. - 50.).max(0.);
+                let pivot = (rng.gen_range(-w_span..w_span), gen_range_or_min(rng, (0., h_span)));
 
                 // 円
-                let radius = (rng.gen_range(80.0..150.), 100.);
+                let radius = (
+                    gen_range_or_min(rng, config.enemy.formation_radius_x),
+                    gen_range_or_min(rng, config.enemy.formation_radius_y),
+                );
 
                 //角度
                 let angle = (y - pivot.1).atan2(x - pivot.0);
 
-                // スピード
-                let speed = BASE_SPEED;
-
                 // フォーメーションをまとめる
                 let formation = Formation {
+                    id: self.next_id,
+                    members,
                     start,
                     radius,
                     pivot,
@@ -64,6 +94,7 @@ impl FormationMaker {
                 self.current_template = Some(formation.clone());
 
                 self.current_members = 1;
+                self.next_id += 1;
 
                 formation
             }
@@ -1,105 +1,214 @@
+use self::boss::{BossTypes, BOSS_TYPES_FILE};
 use self::formation::{Formation, FormationMaker};
-use crate::components::{Enemy, FromEnemy, Laser, Movable, SpriteSize, Velocity};
-use crate::{
-    EnemyCount, GameTextures, WinSize, ENEMY_LASER_SIZE, ENEMY_MAX, ENEMY_SIZE, SPRITE_SCALE,
-    TIME_STEP,
+use self::bullet::{BulletState, EnemyWeapon, FireCheck};
+use self::types::{EnemyMovement, EnemyTypes, ENEMY_TYPES_FILE};
+use self::wave::{Difficulty, WaveDefs, WaveStarted, WaveState, WAVES_FILE};
+use crate::components::{
+    BaseColor, Damage, Enemy, EnemyKind, FiredBy, Health, HitFlash, Player, Points,
 };
-use bevy::core::FixedTimestep;
-use bevy::ecs::schedule::ShouldRun;
+use crate::clock::{SimAppExt, SimStage, SimTime};
+use crate::collision::{Collider, CollisionLayers, Layer};
+use crate::rng::GameRng;
+use crate::state::AppState;
+use crate::config::{ConfigReloaded, GameConfig};
+use crate::{EnemyCount, GameSystem, GameTextures, Playfield};
 use bevy::prelude::*;
-use rand::{thread_rng, Rng};
 use std::f32::consts::PI;
+use std::path::Path;
 
-mod formation;
+pub mod boss;
+pub mod bullet;
+pub mod formation;
+pub mod types;
+pub mod wave;
 
 pub struct EnemyPlugin;
 
 impl Plugin for EnemyPlugin {
     fn build(&self, app: &mut App) {
+        let enemy_types = EnemyTypes::load_or_default(Path::new(ENEMY_TYPES_FILE));
+        let boss_types = BossTypes::load_or_default(Path::new(BOSS_TYPES_FILE));
+        let wave_defs = WaveDefs::load_or_default(Path::new(WAVES_FILE), &enemy_types, &boss_types);
+        let difficulty = Difficulty::for_level(&wave_defs.scaling, 1);
+
         app.insert_resource(FormationMaker::default())
+            .insert_resource(enemy_types)
+            .insert_resource(boss_types)
+            .insert_resource(wave_defs)
+            .insert_resource(difficulty)
+            .insert_resource(WaveState::default())
+            .add_event::<WaveStarted>()
+            .add_system_set(SystemSet::on_enter(AppState::Playing).with_system(wave::wave_reset_system))
+            //tick が進まないフレームが続いても WaveStarted を読み逃さないように毎フレーム読む
+            .add_system_set(SystemSet::on_update(AppState::Playing).with_system(wave::wave_banner_system))
             .add_system_set(
-            SystemSet::new()
-                .with_run_criteria(FixedTimestep::step(1.))
-                .with_system(enemy_spawn_system), )
-            .add_system_set(
+                SystemSet::on_exit(AppState::Playing).with_system(wave::wave_banner_cleanup_system),
+            )
+            .add_sim_system_set(
+                SimStage::Update,
+                SystemSet::new()
+                    .label(GameSystem::Spawn)
+                    .after(GameSystem::Collision)
+                    .with_system(wave::wave_progress_system)
+                    .with_system(enemy_spawn_system.after(wave::wave_progress_system))
+                    //乱数を使う順番を固定する
+                    .with_system(boss::boss_spawn_system.after(enemy_spawn_system)),
+            )
+            .add_sim_system_set(
+                SimStage::Update,
+                SystemSet::new()
+                    .label(GameSystem::Fire)
+                    .after(GameSystem::Input)
+                    .with_system(enemy_fire_system)
+                    .with_system(boss::boss_fire_system.after(enemy_fire_system))
+                    .with_system(bullet::bullet_burst_system),
+            )
+            .add_sim_system_set(
+                SimStage::Update,
                 SystemSet::new()
-                    .with_run_criteria(enemy_fire_criteria)
-                    .with_system(enemy_fire_system), )
-            .add_system(enemy_movement_system);
+                    .label(GameSystem::Movement)
+                    .after(GameSystem::Fire)
+                    .with_system(enemy_movement_system)
+                    .with_system(boss::boss_movement_system),
+            )
+            .add_sim_system_set(
+                SimStage::Update,
+                SystemSet::new()
+                    .label(GameSystem::Collision)
+                    .after(GameSystem::Movement)
+                    //倒した順にパワーアップを落とすので順番を固定する
+                    .with_system(boss::boss_damage_system.after(crate::enemy_damage_system)),
+            )
+            .add_sim_system_set(
+                SimStage::Update,
+                SystemSet::new()
+                    .with_system(enemy_hit_flash_system)
+                    .with_system(boss::chain_explosion_system),
+            )
+            .add_system(enemy_config_reload_system);
     }
 }
 
+//ウェーブの予定に従って敵を出現させる
+#[allow(clippy::too_many_arguments)]
 fn enemy_spawn_system(
     mut commands: Commands,
-    game_textures: Res<GameTextures>,
+    sim_time: Res<SimTime>,
+    config: Res<GameConfig>,
+    enemy_types: Res<EnemyTypes>,
+    wave_defs: Res<WaveDefs>,
+    difficulty: Res<Difficulty>,
+    mut wave_state: ResMut<WaveState>,
     mut enemy_count: ResMut<EnemyCount>,
     mut formation_maker: ResMut<FormationMaker>,
-    win_size: Res<WinSize>,
+    mut rng: ResMut<GameRng>,
+    playfield: Res<Playfield>,
 ) {
-    if enemy_count.0 < ENEMY_MAX {
+    if let Some(order) = wave_state.next_spawn(&wave_defs, &difficulty, sim_time.tick(), enemy_count.0) {
+        //ウェーブの定義は読み込み時に検証済み
+        let enemy_type = match enemy_types.get(&order.enemy) {
+            Some(enemy_type) => enemy_type,
+            None => return,
+        };
+        if order.new_formation {
+            formation_maker.reset();
+        }
         // 初期フォーメーション設定
-        let formation = formation_maker.make(&win_size);
+        let speed = config.enemy.speed * enemy_type.speed * difficulty.speed;
+        let formation = formation_maker.make(&playfield, &config, order.members, speed, &mut *rng);
         let (x, y) = formation.start;
+        let scale = config.sprites.scale * enemy_type.scale;
 
         commands.spawn_bundle(SpriteBundle {
-            texture: game_textures.enemy.clone(),
+            texture: enemy_type.texture.clone(),
+            sprite: Sprite {
+                color: enemy_type.color(),
+                ..Default::default()
+            },
             transform: Transform {
                 translation: Vec3::new(x, y, 10.),
-                scale: Vec3::new(SPRITE_SCALE * 1. / 2., SPRITE_SCALE * 1. / 2., 1.),
+                scale: Vec3::new(scale, scale, 1.),
                 ..Default::default()
             },
             ..Default::default()
         })
             .insert(Enemy)
+            .insert(EnemyKind(order.enemy))
             .insert(formation)
-            .insert(SpriteSize::from(ENEMY_SIZE));
+            .insert(enemy_type.movement)
+            .insert(enemy_type.weapon)
+            .insert(BulletState::new(
+                &enemy_type.weapon,
+                sim_time.tick(),
+                config.enemy.fire_rate * difficulty.fire_rate,
+                &mut *rng,
+            ))
+            .insert(enemy_type.hitbox.map_or_else(|| Collider::sprite_box(config.enemy.size), Collider))
+            .insert(CollisionLayers::new(Layer::Enemy, &config.collision.enemy))
+            .insert(Health(enemy_type.health))
+            .insert(Damage(1))
+            .insert(BaseColor(enemy_type.color()))
+            .insert(Points(enemy_type.points));
 
         enemy_count.0 += 1;
     }
 }
 
-fn enemy_fire_criteria() -> ShouldRun {
-    if thread_rng().gen_bool(1. / 60.) {
-        ShouldRun::Yes
-    } else {
-        ShouldRun::No
-    }
-}
+type EnemyFireQuery<'a> = (Entity, &'a Transform, &'a EnemyWeapon, &'a mut BulletState);
 
+//敵ごとの間隔で撃つ
+#[allow(clippy::too_many_arguments)]
 fn enemy_fire_system(
     mut commands: Commands,
+    sim_time: Res<SimTime>,
+    config: Res<GameConfig>,
+    difficulty: Res<Difficulty>,
     game_textures: Res<GameTextures>,
-    enemy_query: Query<&Transform, With<Enemy>>,
+    mut rng: ResMut<GameRng>,
+    player_query: Query<&Transform, With<Player>>,
+    bullet_query: Query<&FiredBy>,
+    mut enemy_query: Query<EnemyFireQuery, With<Enemy>>,
 ) {
-    for &tf in enemy_query.iter() {
-        let (x, y) = (tf.translation.x, tf.translation.y);
-        //敵のレーザーを生成する
-        commands
-            .spawn_bundle(SpriteBundle {
-                texture: game_textures.enemy_laser.clone(),
-                transform: Transform {
-                    translation: Vec3::new(x, y - 15., 0.),
-                    rotation: Quat::from_rotation_x(PI),
-                    scale: Vec3::new(SPRITE_SCALE, SPRITE_SCALE, 1.),
-                    ..Default::default()
-                },
-                ..Default::default()
-            })
-            .insert(Laser)
-            .insert(SpriteSize::from(ENEMY_LASER_SIZE))
-            .insert(FromEnemy)
-            .insert(Movable { auto_despawn: true })
-            .insert(Velocity { x: 0., y: -1. });
+    let players: Vec<Vec3> = player_query.iter().map(|tf| tf.translation).collect();
+    let live_bullets = bullet::count_live_bullets(bullet_query.iter());
+    let allies: Vec<(Entity, Vec3)> = enemy_query
+        .iter()
+        .map(|(entity, tf, _, _)| (entity, tf.translation))
+        .collect();
+    let rate = config.enemy.fire_rate * difficulty.fire_rate;
+
+    for (entity, tf, weapon, mut state) in enemy_query.iter_mut() {
+        let check = FireCheck {
+            shooter: entity,
+            origin: tf.translation,
+            target: bullet::nearest_player(&players, tf.translation),
+            live_bullets: &live_bullets,
+            allies: &allies,
+        };
+        bullet::try_fire(
+            &mut commands,
+            &config,
+            &game_textures,
+            &mut *rng,
+            sim_time.tick(),
+            rate,
+            &check,
+            weapon,
+            &mut state,
+        );
     }
 }
 
-fn enemy_movement_system(mut query: Query<(&mut Transform, &mut Formation), With<Enemy>>) {
-    for (mut transform, mut formation) in query.iter_mut() {
+fn enemy_movement_system(
+    sim_time: Res<SimTime>,
+    mut query: Query<(&mut Transform, &mut Formation, &EnemyMovement), With<Enemy>>,
+) {
+    for (mut transform, mut formation, movement) in query.iter_mut() {
         //現在地
         let (x_org, y_org) = (transform.translation.x, transform.translation.y);
 
         //最大距離
-        let max_distance = TIME_STEP * formation.speed;
+        let max_distance = sim_time.delta_seconds() * formation.speed;
 
         let dir: f32 = if formation.start.0 < 0. { 1. } else { -1. };
         let (x_pivot, y_pivot) = formation.pivot;
@@ -107,11 +216,13 @@ fn enemy_movement_system(mut query: Query<(&mut Transform, &mut Formation), With
 
         // 角度を生成
         let angle = formation.angle
-            + dir * formation.speed * TIME_STEP / (x_radius.min(y_radius) * PI / 2.);
+            + dir * formation.speed * sim_time.delta_seconds() / (x_radius.min(y_radius) * PI / 2.);
 
         // 回転の中心
-        let x_dst = x_radius * angle.cos() + x_pivot;
-        let y_dst = y_radius * angle.sin() + y_pivot;
+        let (x_dst, y_dst) = match movement {
+            EnemyMovement::Orbit => (x_radius * angle.cos() + x_pivot, y_radius * angle.sin() + y_pivot),
+            EnemyMovement::Sweep => (x_radius * 1.5 * angle.cos() + x_pivot, y_pivot),
+        };
 
         // 中心からの距離
         let dx = x_org - x_dst;
@@ -133,4 +244,37 @@ fn enemy_movement_system(mut query: Query<(&mut Transform, &mut Formation), With
         let translation = &mut transform.translation;
         (translation.x, translation.y) = (x, y);
     }
-}
\ No newline at end of file
+}
+
+//設定が再読み込みされたら、出現中の敵の速さを更新する
+fn enemy_config_reload_system(
+    mut events: EventReader<ConfigReloaded>,
+    config: Res<GameConfig>,
+    difficulty: Res<Difficulty>,
+    enemy_types: Res<EnemyTypes>,
+    mut query: Query<(&mut Formation, &EnemyKind), With<Enemy>>,
+) {
+    if events.iter().last().is_none() {
+        return;
+    }
+
+    for (mut formation, kind) in query.iter_mut() {
+        if let Some(enemy_type) = enemy_types.get(&kind.0) {
+            formation.speed = config.enemy.speed * enemy_type.speed * difficulty.speed;
+        }
+    }
+}
+
+//点滅が終わったら元の色に戻す
+fn enemy_hit_flash_system(
+    mut commands: Commands,
+    sim_time: Res<SimTime>,
+    mut query: Query<(Entity, &mut HitFlash, &mut Sprite, &BaseColor)>,
+) {
+    for (entity, mut flash, mut sprite, base_color) in query.iter_mut() {
+        if flash.0.tick(sim_time.delta()).finished() {
+            sprite.color = base_color.0;
+            commands.entity(entity).remove::<HitFlash>();
+        }
+    }
+}
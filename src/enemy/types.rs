@@ -0,0 +1,128 @@
+use super::bullet::EnemyWeapon;
+use crate::collision::Shape;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+pub const ENEMY_TYPES_FILE: &str = "config/enemies.ron";
+pub const DEFAULT_ENEMY_TYPE: &str = "basic";
+
+//動き方
+#[derive(Debug, Clone, Copy, PartialEq, Component, Serialize, Deserialize)]
+pub enum EnemyMovement {
+    //フォーメーションの楕円を周回する
+    Orbit,
+    //ピボットの高さで左右に往復する
+    Sweep,
+}
+
+//敵の種類ごとの設定
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EnemyType {
+    pub sprite: String,
+    //スプライトの色 (r, g, b)
+    pub color: (f32, f32, f32),
+    //sprites.scale に掛ける大きさ
+    pub scale: f32,
+    pub health: u32,
+    pub points: u32,
+    //enemy.speed に掛ける速さ
+    pub speed: f32,
+    pub movement: EnemyMovement,
+    pub weapon: EnemyWeapon,
+    //当たり判定の形。省略した場合は enemy.size の箱
+    pub hitbox: Option<Shape>,
+    //起動時に setup_system で読み込む。ヘッドレスモードではダミーのまま
+    #[serde(skip)]
+    pub texture: Handle<Image>,
+}
+
+impl Default for EnemyType {
+    fn default() -> Self {
+        Self {
+            sprite: "Enemy.png".to_string(),
+            color: (1., 1., 1.),
+            scale: 0.5,
+            health: 1,
+            points: 100,
+            speed: 1.,
+            movement: EnemyMovement::Orbit,
+            weapon: EnemyWeapon::default(),
+            hitbox: None,
+            texture: Handle::default(),
+        }
+    }
+}
+
+impl EnemyType {
+    pub fn color(&self) -> Color {
+        let (r, g, b) = self.color;
+        Color::rgb(r, g, b)
+    }
+}
+
+//敵の種類の一覧。ウェーブの定義から名前で参照する
+pub struct EnemyTypes(HashMap<String, EnemyType>);
+
+impl Default for EnemyTypes {
+    fn default() -> Self {
+        let mut types = HashMap::new();
+        types.insert(DEFAULT_ENEMY_TYPE.to_string(), EnemyType::default());
+        Self(types)
+    }
+}
+
+impl EnemyTypes {
+    pub fn load_or_default(path: &Path) -> Self {
+        let result = fs::read_to_string(path)
+            .map_err(|err| format!("failed to read {}: {}", path.display(), err))
+            .and_then(|text| {
+                ron::from_str::<HashMap<String, EnemyType>>(&text)
+                    .map_err(|err| format!("failed to parse {}: {}", path.display(), err))
+            })
+            .map(Self)
+            .and_then(|types| {
+                types
+                    .validate()
+                    .map(|_| types)
+                    .map_err(|err| format!("invalid enemy types {}: {}", path.display(), err))
+            });
+        result.unwrap_or_else(|err| {
+            eprintln!("{}; using default enemy types", err);
+            Self::default()
+        })
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.0.is_empty() {
+            return Err("at least one enemy type is required".to_string());
+        }
+        for (name, enemy_type) in &self.0 {
+            if enemy_type.health == 0 {
+                return Err(format!("{}: health must be at least 1", name));
+            }
+            if !(enemy_type.scale > 0. && enemy_type.speed > 0.) {
+                return Err(format!("{}: scale and speed must be positive", name));
+            }
+            enemy_type.weapon.validate().map_err(|err| format!("{}: {}", name, err))?;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&EnemyType> {
+        self.0.get(name)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.contains_key(name)
+    }
+
+    pub fn load_textures(&mut self, asset_server: &AssetServer) {
+        for enemy_type in self.0.values_mut() {
+            enemy_type.texture = asset_server.load(enemy_type.sprite.as_str());
+        }
+    }
+}
@@ -0,0 +1,395 @@
+use super::boss::BossTypes;
+use super::types::{EnemyTypes, DEFAULT_ENEMY_TYPE};
+use crate::clock::{seconds_to_ticks, SimTime};
+use crate::state::UiFont;
+use crate::EnemyCount;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+pub const WAVES_FILE: &str = "config/waves.ron";
+//ウェーブ開始前にバナーを表示する秒数
+const INTRO_SECONDS: f64 = 2.;
+
+//1つのフォーメーションとして続けて出現させる敵
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FormationSpawn {
+    #[serde(default = "FormationSpawn::default_enemy")]
+    pub enemy: String,
+    pub members: u32,
+}
+
+impl FormationSpawn {
+    fn default_enemy() -> String {
+        DEFAULT_ENEMY_TYPE.to_string()
+    }
+}
+
+//ウェーブのクリア条件
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum ClearCondition {
+    //全ての敵を出現させて、全て倒した
+    Destroyed,
+    //全ての敵を出現させてから指定の秒数が経過した
+    Time(f64),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WaveDef {
+    #[serde(default)]
+    pub formations: Vec<FormationSpawn>,
+    //ウェーブ開始時に出現するボス (BossTypes のキー)
+    #[serde(default)]
+    pub boss: Option<String>,
+    //敵を1体出現させる間隔 (秒)
+    pub spawn_interval: f64,
+    //同時に出現できる敵の数
+    pub max_alive: u32,
+    pub clear: ClearCondition,
+}
+
+//レベルが1上がるごとの難易度の上昇率
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DifficultyScaling {
+    pub speed: f32,
+    pub fire_rate: f64,
+    pub spawn_rate: f64,
+}
+
+impl Default for DifficultyScaling {
+    fn default() -> Self {
+        Self {
+            speed: 0.1,
+            fire_rate: 0.25,
+            spawn_rate: 0.1,
+        }
+    }
+}
+
+//ウェーブの定義。全ウェーブをクリアするとレベルが上がって最初のウェーブに戻る
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WaveDefs {
+    pub waves: Vec<WaveDef>,
+    #[serde(default)]
+    pub scaling: DifficultyScaling,
+}
+
+impl Default for WaveDefs {
+    fn default() -> Self {
+        Self {
+            waves: vec![WaveDef {
+                formations: vec![
+                    FormationSpawn {
+                        enemy: FormationSpawn::default_enemy(),
+                        members: 2,
+                    };
+                    3
+                ],
+                boss: None,
+                spawn_interval: 1.,
+                max_alive: 2,
+                clear: ClearCondition::Destroyed,
+            }],
+            scaling: DifficultyScaling::default(),
+        }
+    }
+}
+
+impl WaveDefs {
+    pub fn load_or_default(path: &Path, types: &EnemyTypes, bosses: &BossTypes) -> Self {
+        let result = fs::read_to_string(path)
+            .map_err(|err| format!("failed to read {}: {}", path.display(), err))
+            .and_then(|text| {
+                ron::from_str::<Self>(&text)
+                    .map_err(|err| format!("failed to parse {}: {}", path.display(), err))
+            })
+            .and_then(|defs| {
+                defs.validate(types, bosses)
+                    .map(|_| defs)
+                    .map_err(|err| format!("invalid waves {}: {}", path.display(), err))
+            });
+        result.unwrap_or_else(|err| {
+            eprintln!("{}; using default waves", err);
+            Self::default()
+        })
+    }
+
+    pub fn validate(&self, types: &EnemyTypes, bosses: &BossTypes) -> Result<(), String> {
+        if self.waves.is_empty() {
+            return Err("at least one wave is required".to_string());
+        }
+        for (i, wave) in self.waves.iter().enumerate() {
+            if wave.formations.is_empty() && wave.boss.is_none() {
+                return Err(format!("wave {} needs formations or a boss", i + 1));
+            }
+            if wave.formations.iter().any(|f| f.members == 0) {
+                return Err(format!("wave {}: formations need at least one member", i + 1));
+            }
+            if let Some(boss) = wave.boss.as_ref().filter(|boss| !bosses.contains(boss)) {
+                return Err(format!("wave {}: unknown boss {}", i + 1, boss));
+            }
+            if let Some(spawn) = wave.formations.iter().find(|f| !types.contains(&f.enemy)) {
+                return Err(format!("wave {}: unknown enemy type {}", i + 1, spawn.enemy));
+            }
+            if wave.max_alive == 0 {
+                return Err(format!("wave {}: max_alive must be at least 1", i + 1));
+            }
+            if wave.spawn_interval <= 0. || wave.spawn_interval.is_nan() {
+                return Err(format!("wave {}: spawn_interval must be positive", i + 1));
+            }
+        }
+        Ok(())
+    }
+}
+
+//今のレベルでの難易度の倍率
+pub struct Difficulty {
+    pub speed: f32,
+    //攻撃の頻度の倍率
+    pub fire_rate: f64,
+    pub spawn_interval: f64,
+}
+
+impl Difficulty {
+    pub fn for_level(scaling: &DifficultyScaling, level: u32) -> Self {
+        let steps = level.saturating_sub(1);
+        Self {
+            speed: 1. + scaling.speed * steps as f32,
+            fire_rate: 1. + scaling.fire_rate * steps as f64,
+            spawn_interval: 1. / (1. + scaling.spawn_rate * steps as f64),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WavePhase {
+    //ウェーブ開始前のバナー表示中
+    Intro { until: u64 },
+    //敵を出現させている
+    Active,
+}
+
+//ウェーブの進行状況
+pub struct WaveState {
+    pub level: u32,
+    pub wave: usize,
+    phase: WavePhase,
+    formation: usize,
+    spawned: u32,
+    next_spawn: u64,
+    all_spawned_at: Option<u64>,
+    //新しいフォーメーションを始めるか
+    new_formation: bool,
+    boss_spawned: bool,
+}
+
+impl Default for WaveState {
+    fn default() -> Self {
+        Self {
+            level: 1,
+            wave: 0,
+            phase: WavePhase::Intro { until: 0 },
+            formation: 0,
+            spawned: 0,
+            next_spawn: 0,
+            all_spawned_at: None,
+            new_formation: true,
+            boss_spawned: false,
+        }
+    }
+}
+
+//敵を1体出現させる指示
+pub struct SpawnOrder {
+    pub enemy: String,
+    pub members: u32,
+    pub new_formation: bool,
+}
+
+impl WaveState {
+    fn start_wave(&mut self, wave: usize, now: u64) {
+        self.wave = wave;
+        self.phase = WavePhase::Intro {
+            until: now + seconds_to_ticks(INTRO_SECONDS),
+        };
+        self.formation = 0;
+        self.spawned = 0;
+        self.all_spawned_at = None;
+        self.new_formation = true;
+        self.boss_spawned = false;
+    }
+
+    /// ボスを出現させるウェーブなら、一度だけその名前を返す
+    pub fn take_boss(&mut self, defs: &WaveDefs) -> Option<String> {
+        if self.phase != WavePhase::Active || self.boss_spawned {
+            return None;
+        }
+        let boss = defs.waves[self.wave].boss.clone()?;
+        self.boss_spawned = true;
+        Some(boss)
+    }
+
+    /// 出現させる敵があれば返して、進行状況を進める
+    pub fn next_spawn(
+        &mut self,
+        defs: &WaveDefs,
+        difficulty: &Difficulty,
+        now: u64,
+        alive: u32,
+    ) -> Option<SpawnOrder> {
+        let wave = &defs.waves[self.wave];
+        if self.phase != WavePhase::Active
+            || self.formation >= wave.formations.len()
+            || now < self.next_spawn
+            || alive >= wave.max_alive
+        {
+            return None;
+        }
+
+        let spawn = &wave.formations[self.formation];
+        let order = SpawnOrder {
+            enemy: spawn.enemy.clone(),
+            members: spawn.members,
+            new_formation: self.new_formation,
+        };
+
+        self.new_formation = false;
+        self.spawned += 1;
+        if self.spawned >= spawn.members {
+            self.formation += 1;
+            self.spawned = 0;
+            self.new_formation = true;
+        }
+        self.next_spawn = now + seconds_to_ticks(wave.spawn_interval * difficulty.spawn_interval).max(1);
+
+        Some(order)
+    }
+}
+
+//ウェーブ開始時のバナー
+#[derive(Component)]
+pub struct WaveBanner;
+
+//ウェーブ開始時に送る
+pub struct WaveStarted {
+    pub level: u32,
+    pub wave: usize,
+}
+
+//ゲーム開始時は SimTime も 0 に戻るので、tick 0 から始める
+pub fn wave_reset_system(
+    defs: Res<WaveDefs>,
+    mut wave_state: ResMut<WaveState>,
+    mut difficulty: ResMut<Difficulty>,
+    mut events: EventWriter<WaveStarted>,
+) {
+    *wave_state = WaveState::default();
+    wave_state.start_wave(0, 0);
+    *difficulty = Difficulty::for_level(&defs.scaling, 1);
+    events.send(WaveStarted { level: 1, wave: 0 });
+}
+
+//クリア条件を満たしたら次のウェーブへ進める
+pub fn wave_progress_system(
+    sim_time: Res<SimTime>,
+    enemy_count: Res<EnemyCount>,
+    defs: Res<WaveDefs>,
+    mut wave_state: ResMut<WaveState>,
+    mut difficulty: ResMut<Difficulty>,
+    mut events: EventWriter<WaveStarted>,
+) {
+    let now = sim_time.tick();
+    match wave_state.phase {
+        WavePhase::Intro { until } => {
+            if now >= until {
+                wave_state.phase = WavePhase::Active;
+                wave_state.next_spawn = now;
+            }
+        }
+        WavePhase::Active => {
+            let wave = &defs.waves[wave_state.wave];
+            if wave_state.all_spawned_at.is_none()
+                && wave_state.formation >= wave.formations.len()
+                && (wave.boss.is_none() || wave_state.boss_spawned)
+            {
+                wave_state.all_spawned_at = Some(now);
+            }
+            let all_spawned_at = match wave_state.all_spawned_at {
+                Some(tick) => tick,
+                None => return,
+            };
+            let cleared = match wave.clear {
+                ClearCondition::Destroyed => enemy_count.0 == 0,
+                ClearCondition::Time(seconds) => now >= all_spawned_at + seconds_to_ticks(seconds),
+            };
+            if !cleared {
+                return;
+            }
+
+            let mut next = wave_state.wave + 1;
+            if next >= defs.waves.len() {
+                //全ウェーブをクリアしたらレベルアップ
+                next = 0;
+                wave_state.level += 1;
+                *difficulty = Difficulty::for_level(&defs.scaling, wave_state.level);
+            }
+            wave_state.start_wave(next, now);
+            events.send(WaveStarted {
+                level: wave_state.level,
+                wave: next,
+            });
+        }
+    }
+}
+
+pub fn wave_banner_system(
+    mut commands: Commands,
+    font: Res<UiFont>,
+    wave_state: Res<WaveState>,
+    mut events: EventReader<WaveStarted>,
+    query: Query<Entity, With<WaveBanner>>,
+) {
+    for event in events.iter() {
+        commands
+            .spawn_bundle(TextBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    position: Rect {
+                        top: Val::Percent(40.),
+                        left: Val::Percent(35.),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+                text: Text::with_section(
+                    format!("LEVEL {}\nWAVE {}", event.level, event.wave + 1),
+                    TextStyle {
+                        font: font.0.clone(),
+                        font_size: 40.,
+                        color: Color::WHITE,
+                    },
+                    TextAlignment {
+                        horizontal: HorizontalAlign::Center,
+                        ..Default::default()
+                    },
+                ),
+                ..Default::default()
+            })
+            .insert(WaveBanner);
+    }
+
+    //バナーはウェーブが始まったら消す
+    if wave_state.phase == WavePhase::Active {
+        for entity in query.iter() {
+            commands.entity(entity).despawn();
+        }
+    }
+}
+
+pub fn wave_banner_cleanup_system(mut commands: Commands, query: Query<Entity, With<WaveBanner>>) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn();
+    }
+}
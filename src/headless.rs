@@ -0,0 +1,124 @@
+use crate::cli::LaunchOptions;
+use crate::clock::{SimPacing, SimTime};
+use crate::components::{Enemy, Explosion, Laser, Player};
+use crate::replay::{self, PlaybackStatus, ReplayMode};
+use crate::rng::{GameRng, SeedSetting};
+use crate::score::Score;
+use crate::state::{AppState, UiFont};
+use crate::config::GameConfig;
+use crate::enemy::wave::WaveState;
+use crate::{GamePlugin, GameTextures, Playfield, PlayerCount, Players};
+use bevy::input::InputPlugin;
+use bevy::transform::TransformPlugin;
+use bevy::prelude::*;
+use bevy::window::ReceivedCharacter;
+use std::time::Instant;
+
+/// ウィンドウと GPU なしでゲームロジックを指定 tick 数だけ動かす
+pub fn run(options: &LaunchOptions, config: GameConfig, replay_mode: ReplayMode) {
+    //リプレイ再生時は記録の最後まで動かす
+    let max_ticks = if options.replay.is_some() {
+        u64::MAX
+    } else {
+        options.ticks
+    };
+
+    let mut app = App::new();
+    app.insert_resource(replay_mode)
+        //経過時間に関係なく、できるだけ速く1 tick ずつ進める
+        .insert_resource(SimPacing::Stepped)
+        .add_plugins(MinimalPlugins)
+        .add_plugin(InputPlugin)
+        //ボスのパーツの位置 (GlobalTransform) を親から計算する
+        .add_plugin(TransformPlugin)
+        .add_event::<ReceivedCharacter>()
+        .insert_resource(Playfield::from_config(&config))
+        .insert_resource(config)
+        .insert_resource(GameTextures::default())
+        .insert_resource(UiFont(Handle::default()))
+        .insert_resource(SeedSetting(options.seed))
+        .insert_resource(PlayerCount(options.players))
+        .add_plugin(GamePlugin);
+
+    //タイトル画面を飛ばしてすぐにゲームを始める
+    app.world
+        .resource_mut::<State<AppState>>()
+        .overwrite_set(AppState::Playing)
+        .unwrap();
+
+    //ゲームオーバーで Playing を抜けるとエンティティが片付けられるので、Playing 中の最後の数を報告する
+    let mut counts = EntityCounts::default();
+    let started = Instant::now();
+    while app.world.resource::<SimTime>().tick() < max_ticks {
+        app.update();
+
+        match app.world.resource::<State<AppState>>().current() {
+            AppState::Playing => counts = EntityCounts::new(&mut app.world),
+            AppState::GameOver => break,
+            _ => {}
+        }
+        if let Some(PlaybackStatus::Matched | PlaybackStatus::Mismatched) =
+            app.world.resource::<ReplayMode>().playback_status()
+        {
+            break;
+        }
+    }
+    let elapsed = started.elapsed();
+    let ticks = app.world.resource::<SimTime>().tick();
+
+    //時間切れで終わった場合もここまでの入力を保存する
+    if app.world.resource::<State<AppState>>().current() == &AppState::Playing {
+        replay::flush_recording(&mut app.world);
+    }
+
+    report(&app.world, &counts, ticks, elapsed.as_secs_f64());
+
+    match app.world.resource::<ReplayMode>().playback_status() {
+        Some(PlaybackStatus::Matched) => println!("replay:     OK"),
+        Some(_) => {
+            println!("replay:     MISMATCH");
+            std::process::exit(1);
+        }
+        None => {}
+    }
+}
+
+#[derive(Default)]
+struct EntityCounts {
+    players: usize,
+    enemies: usize,
+    lasers: usize,
+    explosions: usize,
+}
+
+impl EntityCounts {
+    fn new(world: &mut World) -> Self {
+        Self {
+            players: world.query_filtered::<(), With<Player>>().iter(world).count(),
+            enemies: world.query_filtered::<(), With<Enemy>>().iter(world).count(),
+            lasers: world.query_filtered::<(), With<Laser>>().iter(world).count(),
+            explosions: world.query_filtered::<(), With<Explosion>>().iter(world).count(),
+        }
+    }
+}
+
+fn report(world: &World, counts: &EntityCounts, ticks: u64, elapsed: f64) {
+    let state = *world.resource::<State<AppState>>().current();
+    let seed = world.resource::<GameRng>().seed();
+    let score = world.resource::<Score>().total();
+    let lives = world.resource::<Players>().lives();
+    let wave_state = world.resource::<WaveState>();
+    let (level, wave) = (wave_state.level, wave_state.wave + 1);
+
+    println!("seed:       {}", seed);
+    println!("ticks:      {}", ticks);
+    println!("elapsed:    {:.3}s ({:.0} ticks/s)", elapsed, ticks as f64 / elapsed.max(f64::EPSILON));
+    println!("state:      {:?}", state);
+    println!("score:      {}", score);
+    println!("lives:      {}", lives);
+    println!("wave:       {} (level {})", wave, level);
+    println!("players:    {}", counts.players);
+    println!("enemies:    {}", counts.enemies);
+    println!("lasers:     {}", counts.lasers);
+    println!("explosions: {}", counts.explosions);
+}
@@ -0,0 +1,342 @@
+use crate::components::{Player, PlayerId, Weapon, WeaponTier};
+use crate::config::{GameConfig, Language};
+use crate::display::PlayfieldViewport;
+use crate::enemy::wave::WaveState;
+use crate::score::{HighScores, Score};
+use crate::state::{AppState, UiFont};
+use crate::{GameTextures, PlayerCount, Players};
+use bevy::asset::LoadState;
+use bevy::prelude::*;
+
+const HUD_FONT_SIZE: f32 = 20.;
+//画面端からの余白
+const HUD_MARGIN: f32 = 10.;
+//残機アイコンの大きさと、表示する最大数
+const LIFE_ICON_SIZE: f32 = 24.;
+const MAX_LIFE_ICONS: u32 = 8;
+
+//HUD のテキストに表示する値。番号はプレイヤー (PlayerId)
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+enum HudField {
+    Score(usize),
+    HighScore,
+    Wave,
+    Bombs(usize),
+    Weapon(usize),
+}
+
+//HUD の一番上のノード。ゲーム終了時にまとめて削除する
+#[derive(Component)]
+struct HudRoot;
+
+//HUD の表示に使うフォントと言語。日本語のフォントが読み込めなかった場合は UiFont と英語に戻す
+struct HudStyle {
+    font: Handle<Font>,
+    language: Language,
+}
+
+//残機アイコンを並べるノード
+#[derive(Component)]
+struct LifeIcons(usize);
+
+pub struct HudPlugin;
+
+impl Plugin for HudPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set(SystemSet::on_enter(AppState::Playing).with_system(hud_spawn_system))
+            .add_system_set(
+                SystemSet::on_update(AppState::Playing)
+                    .with_system(hud_font_fallback_system)
+                    .with_system(hud_text_system.after(hud_font_fallback_system))
+                    .with_system(hud_life_icons_system)
+                    .with_system(hud_viewport_system),
+            )
+            .add_system_set(SystemSet::on_exit(AppState::Playing).with_system(hud_cleanup_system));
+    }
+}
+
+fn label(language: Language, field: HudField) -> &'static str {
+    match (language, field) {
+        (Language::English, HudField::Score(_)) => "SCORE",
+        (Language::English, HudField::HighScore) => "HI-SCORE",
+        (Language::English, HudField::Wave) => "WAVE",
+        (Language::English, HudField::Bombs(_)) => "BOMB",
+        (Language::English, HudField::Weapon(_)) => "WEAPON",
+        (Language::Japanese, HudField::Score(_)) => "スコア",
+        (Language::Japanese, HudField::HighScore) => "ハイスコア",
+        (Language::Japanese, HudField::Wave) => "ウェーブ",
+        (Language::Japanese, HudField::Bombs(_)) => "ボム",
+        (Language::Japanese, HudField::Weapon(_)) => "武器",
+    }
+}
+
+fn tier_name(language: Language, tier: WeaponTier) -> &'static str {
+    match (language, tier) {
+        (Language::English, WeaponTier::Single) => "SINGLE",
+        (Language::English, WeaponTier::Twin) => "TWIN",
+        (Language::English, WeaponTier::Triple) => "TRIPLE",
+        (Language::English, WeaponTier::Beam) => "BEAM",
+        (Language::Japanese, WeaponTier::Single) => "シングル",
+        (Language::Japanese, WeaponTier::Twin) => "ツイン",
+        (Language::Japanese, WeaponTier::Triple) => "トリプル",
+        (Language::Japanese, WeaponTier::Beam) => "ビーム",
+    }
+}
+
+//プレイフィールドの上端・下端に横いっぱいに広げた行
+fn row_bundle(top: bool) -> NodeBundle {
+    let mut position = Rect {
+        left: Val::Px(0.),
+        ..Default::default()
+    };
+    if top {
+        position.top = Val::Px(0.);
+    } else {
+        position.bottom = Val::Px(0.);
+    }
+    NodeBundle {
+        style: Style {
+            position_type: PositionType::Absolute,
+            position,
+            size: Size::new(Val::Percent(100.), Val::Auto),
+            justify_content: JustifyContent::SpaceBetween,
+            align_items: AlignItems::Center,
+            padding: Rect::all(Val::Px(HUD_MARGIN)),
+            ..Default::default()
+        },
+        color: Color::NONE.into(),
+        ..Default::default()
+    }
+}
+
+fn text_bundle(font: &Handle<Font>) -> TextBundle {
+    TextBundle {
+        text: Text::with_section(
+            "",
+            TextStyle {
+                font: font.clone(),
+                font_size: HUD_FONT_SIZE,
+                color: Color::WHITE,
+            },
+            Default::default(),
+        ),
+        ..Default::default()
+    }
+}
+
+/// HUD をプレイフィールドが表示されている範囲に重ねる。ウィンドウがない場合は画面全体
+fn root_style(viewport: Option<&PlayfieldViewport>) -> Style {
+    let (position, size) = match viewport {
+        Some(viewport) => (
+            Rect {
+                left: Val::Px(viewport.left),
+                top: Val::Px(viewport.top),
+                ..Default::default()
+            },
+            Size::new(Val::Px(viewport.width), Val::Px(viewport.height)),
+        ),
+        None => (Rect::default(), Size::new(Val::Percent(100.), Val::Percent(100.))),
+    };
+    Style {
+        position_type: PositionType::Absolute,
+        position,
+        size,
+        ..Default::default()
+    }
+}
+
+fn hud_spawn_system(
+    mut commands: Commands,
+    config: Res<GameConfig>,
+    player_count: Res<PlayerCount>,
+    ui_font: Res<UiFont>,
+    asset_server: Option<Res<AssetServer>>,
+    viewport: Option<Res<PlayfieldViewport>>,
+) {
+    //FiraSans には日本語の文字がないので、日本語の時は別のフォントを使う
+    let style = match (config.hud.language, asset_server) {
+        (Language::Japanese, Some(asset_server)) => HudStyle {
+            font: asset_server.load(config.hud.cjk_font.as_str()),
+            language: Language::Japanese,
+        },
+        _ => HudStyle {
+            font: ui_font.0.clone(),
+            language: Language::English,
+        },
+    };
+    let font = style.font.clone();
+    commands.insert_resource(style);
+
+    commands
+        .spawn_bundle(NodeBundle {
+            style: root_style(viewport.as_deref()),
+            color: Color::NONE.into(),
+            ..Default::default()
+        })
+        .insert(HudRoot)
+        .with_children(|root| {
+            //2P のスコアは右端に出す
+            root.spawn_bundle(row_bundle(true)).with_children(|row| {
+                let mut fields = vec![HudField::Score(0), HudField::HighScore, HudField::Wave];
+                fields.extend((1..player_count.0).map(HudField::Score));
+                for field in fields {
+                    row.spawn_bundle(text_bundle(&font)).insert(field);
+                }
+            });
+            //下端はプレイヤーごとに横に分けて、残機・武器・ボムを並べる
+            root.spawn_bundle(row_bundle(false)).with_children(|row| {
+                for player in 0..player_count.0 {
+                    row.spawn_bundle(NodeBundle {
+                        style: Style {
+                            size: Size::new(Val::Percent(100. / player_count.0 as f32), Val::Auto),
+                            justify_content: JustifyContent::SpaceBetween,
+                            align_items: AlignItems::Center,
+                            padding: Rect::all(Val::Px(HUD_MARGIN / 2.)),
+                            ..Default::default()
+                        },
+                        color: Color::NONE.into(),
+                        ..Default::default()
+                    })
+                    .with_children(|group| {
+                        group
+                            .spawn_bundle(NodeBundle {
+                                color: Color::NONE.into(),
+                                ..Default::default()
+                            })
+                            .insert(LifeIcons(player));
+                        for field in [HudField::Weapon(player), HudField::Bombs(player)] {
+                            group.spawn_bundle(text_bundle(&font)).insert(field);
+                        }
+                    });
+                }
+            });
+        });
+}
+
+//日本語のフォントの読み込みに失敗したら、文字が表示されないままにならないように UiFont と英語に切り替える
+fn hud_font_fallback_system(
+    asset_server: Option<Res<AssetServer>>,
+    ui_font: Res<UiFont>,
+    mut style: ResMut<HudStyle>,
+    mut query: Query<&mut Text, With<HudField>>,
+) {
+    let asset_server = match asset_server {
+        Some(asset_server) => asset_server,
+        None => return,
+    };
+    if style.font == ui_font.0 || asset_server.get_load_state(&style.font) != LoadState::Failed {
+        return;
+    }
+    warn!("failed to load the HUD font; falling back to English");
+    style.font = ui_font.0.clone();
+    style.language = Language::English;
+    for mut text in query.iter_mut() {
+        for section in text.sections.iter_mut() {
+            section.style.font = style.font.clone();
+        }
+    }
+}
+
+//値が変わった時だけテキストを書き換える
+#[allow(clippy::too_many_arguments)]
+fn hud_text_system(
+    style: Res<HudStyle>,
+    score: Res<Score>,
+    high_scores: Res<HighScores>,
+    wave_state: Res<WaveState>,
+    players: Res<Players>,
+    player_count: Res<PlayerCount>,
+    weapon_query: Query<(&PlayerId, &Weapon, ChangeTrackers<Weapon>), With<Player>>,
+    added_query: Query<(), Added<HudField>>,
+    mut query: Query<(&HudField, &mut Text)>,
+) {
+    let weapon_changed = weapon_query.iter().any(|(_, _, tracker)| tracker.is_changed());
+    let changed = style.is_changed()
+        || score.is_changed()
+        || high_scores.is_changed()
+        || wave_state.is_changed()
+        || players.is_changed()
+        || weapon_changed
+        || !added_query.is_empty();
+    if !changed {
+        return;
+    }
+
+    let language = style.language;
+    for (field, mut text) in query.iter_mut() {
+        let value = match *field {
+            HudField::Score(player) => score.0[player].to_string(),
+            HudField::HighScore => high_scores.best().max(score.total()).to_string(),
+            HudField::Wave => format!("{}-{}", wave_state.level, wave_state.wave + 1),
+            HudField::Bombs(player) => format!("x{}", players.0[player].bombs),
+            HudField::Weapon(player) => match weapon_query.iter().find(|(id, _, _)| id.0 == player) {
+                Some((_, weapon, _)) => tier_name(language, weapon.tier).to_string(),
+                //やられている間は直前の表示のまま
+                None if !text.sections[0].value.is_empty() => continue,
+                None => tier_name(language, WeaponTier::Single).to_string(),
+            },
+        };
+        //2人の時はスコアに番号を付ける
+        text.sections[0].value = match *field {
+            HudField::Score(player) if player_count.0 > 1 => {
+                format!("{}P {} {}", player + 1, label(language, *field), value)
+            }
+            _ => format!("{} {}", label(language, *field), value),
+        };
+    }
+}
+
+//ウィンドウの大きさが変わって帯の幅が変わったら、HUD をプレイフィールドに合わせ直す
+fn hud_viewport_system(
+    viewport: Option<Res<PlayfieldViewport>>,
+    mut query: Query<&mut Style, With<HudRoot>>,
+) {
+    let viewport = match viewport {
+        Some(viewport) if viewport.is_changed() => viewport,
+        _ => return,
+    };
+    for mut style in query.iter_mut() {
+        *style = root_style(Some(&viewport));
+    }
+}
+
+//残機が変わった時にアイコンを並べ直す
+fn hud_life_icons_system(
+    mut commands: Commands,
+    players: Res<Players>,
+    game_textures: Res<GameTextures>,
+    query: Query<(Entity, &LifeIcons), Added<LifeIcons>>,
+    all_query: Query<(Entity, &LifeIcons)>,
+) {
+    let targets: Vec<(Entity, usize)> = if players.is_changed() {
+        all_query.iter().map(|(entity, icons)| (entity, icons.0)).collect()
+    } else {
+        query.iter().map(|(entity, icons)| (entity, icons.0)).collect()
+    };
+    for (entity, player) in targets {
+        let mut icons = commands.entity(entity);
+        icons.despawn_descendants();
+        icons.with_children(|parent| {
+            for _ in 0..players.0[player].lives.min(MAX_LIFE_ICONS) {
+                parent.spawn_bundle(ImageBundle {
+                    style: Style {
+                        size: Size::new(Val::Px(LIFE_ICON_SIZE), Val::Px(LIFE_ICON_SIZE)),
+                        margin: Rect {
+                            right: Val::Px(4.),
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    },
+                    image: game_textures.player.clone().into(),
+                    ..Default::default()
+                });
+            }
+        });
+    }
+}
+
+fn hud_cleanup_system(mut commands: Commands, query: Query<Entity, With<HudRoot>>) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
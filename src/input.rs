@@ -0,0 +1,355 @@
+use crate::clock::{SimAppExt, SimLabel, SimStage};
+use crate::MAX_PLAYERS;
+use bevy::input::InputSystem;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+
+const CONTROLS_FILE: &str = "controls.ron";
+
+//ゲームの操作。キーボード・ゲームパッドの入力はこの単位に変換してから使う
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Action {
+    MoveLeft = 1 << 0,
+    MoveRight = 1 << 1,
+    Fire = 1 << 2,
+    Bomb = 1 << 3,
+    //押している間は低速で移動する
+    Focus = 1 << 4,
+    Pause = 1 << 5,
+}
+
+impl Action {
+    pub const ALL: [Action; 6] = [
+        Action::MoveLeft,
+        Action::MoveRight,
+        Action::Fire,
+        Action::Bomb,
+        Action::Focus,
+        Action::Pause,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::MoveLeft => "Move Left",
+            Action::MoveRight => "Move Right",
+            Action::Fire => "Fire",
+            Action::Bomb => "Bomb",
+            Action::Focus => "Focus",
+            Action::Pause => "Pause",
+        }
+    }
+}
+
+//ゲームロジック (tick) で使う操作。ポーズは tick の外で扱うのでリプレイに記録しない
+const SIM_ACTIONS: u8 = !(Action::Pause as u8);
+
+/// 全員の1 tick 分の入力を1つの値にまとめる (1P が下位 8 ビット)。リプレイにはこの値を記録する
+pub fn pack_inputs(bits: [u8; MAX_PLAYERS]) -> u16 {
+    bits.iter()
+        .enumerate()
+        .fold(0, |packed, (player, &bits)| packed | (bits as u16) << (player * 8))
+}
+
+fn unpack_inputs(packed: u16) -> [u8; MAX_PLAYERS] {
+    std::array::from_fn(|player| (packed >> (player * 8)) as u8)
+}
+
+//スティックを倒す向き
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AxisDirection {
+    Positive,
+    Negative,
+}
+
+//操作に割り当てる入力
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Binding {
+    Key(KeyCode),
+    Button(GamepadButtonType),
+    //スティック・トリガーを dead_zone より大きく倒す
+    Axis(GamepadAxisType, AxisDirection),
+}
+
+impl Binding {
+    pub fn is_gamepad(self) -> bool {
+        !matches!(self, Binding::Key(_))
+    }
+
+    pub fn label(self) -> String {
+        match self {
+            Binding::Key(key) => format!("{:?}", key),
+            Binding::Button(button) => format!("Pad {:?}", button),
+            Binding::Axis(axis, AxisDirection::Positive) => format!("Pad {:?}+", axis),
+            Binding::Axis(axis, AxisDirection::Negative) => format!("Pad {:?}-", axis),
+        }
+    }
+}
+
+//プレイヤーごと・操作ごとの割り当て。設定メニューで変更し、controls.ron に保存する
+//ゲームパッドは接続した順 (id の小さい順) に 1P, 2P が使う
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Controls {
+    //PlayerId の番号ごとの割り当て
+    pub bindings: Vec<BTreeMap<Action, Vec<Binding>>>,
+    //スティックをこれより小さく倒した時は入力しない (0〜1)
+    pub dead_zone: f32,
+}
+
+impl Default for Controls {
+    fn default() -> Self {
+        Self {
+            bindings: (0..MAX_PLAYERS)
+                .map(|player| {
+                    Action::ALL
+                        .iter()
+                        .map(|&action| (action, Self::default_bindings(player, action)))
+                        .collect()
+                })
+                .collect(),
+            dead_zone: 0.3,
+        }
+    }
+}
+
+impl Controls {
+    /// 2P のキーボードは左手側のキーを使う。ゲームパッドの割り当ては同じ
+    pub fn default_bindings(player: usize, action: Action) -> Vec<Binding> {
+        use self::AxisDirection::*;
+        use self::Binding::*;
+        if player > 0 {
+            return match action {
+                Action::MoveLeft => vec![
+                    Key(KeyCode::A),
+                    Button(GamepadButtonType::DPadLeft),
+                    Axis(GamepadAxisType::LeftStickX, Negative),
+                ],
+                Action::MoveRight => vec![
+                    Key(KeyCode::D),
+                    Button(GamepadButtonType::DPadRight),
+                    Axis(GamepadAxisType::LeftStickX, Positive),
+                ],
+                Action::Fire => vec![Key(KeyCode::F), Button(GamepadButtonType::South)],
+                Action::Bomb => vec![Key(KeyCode::G), Button(GamepadButtonType::East)],
+                Action::Focus => vec![Key(KeyCode::Tab), Button(GamepadButtonType::RightTrigger)],
+                Action::Pause => vec![Button(GamepadButtonType::Start)],
+            };
+        }
+        match action {
+            Action::MoveLeft => vec![
+                Key(KeyCode::Left),
+                Button(GamepadButtonType::DPadLeft),
+                Axis(GamepadAxisType::LeftStickX, Negative),
+            ],
+            Action::MoveRight => vec![
+                Key(KeyCode::Right),
+                Button(GamepadButtonType::DPadRight),
+                Axis(GamepadAxisType::LeftStickX, Positive),
+            ],
+            Action::Fire => vec![Key(KeyCode::Space), Button(GamepadButtonType::South)],
+            Action::Bomb => vec![Key(KeyCode::X), Button(GamepadButtonType::East)],
+            Action::Focus => vec![
+                Key(KeyCode::LShift),
+                Key(KeyCode::RShift),
+                Button(GamepadButtonType::RightTrigger),
+            ],
+            Action::Pause => vec![Key(KeyCode::Escape), Button(GamepadButtonType::Start)],
+        }
+    }
+
+    /// 保存されていないプレイヤー・操作 (古いファイル) はデフォルトの割り当てにする
+    pub fn load() -> Self {
+        let mut controls = match fs::read_to_string(CONTROLS_FILE) {
+            Ok(text) => ron::from_str(&text).unwrap_or_else(|err| {
+                warn!("failed to parse {}: {}", CONTROLS_FILE, err);
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        };
+        controls.bindings.resize_with(MAX_PLAYERS, BTreeMap::new);
+        for (player, bindings) in controls.bindings.iter_mut().enumerate() {
+            for action in Action::ALL {
+                bindings
+                    .entry(action)
+                    .or_insert_with(|| Self::default_bindings(player, action));
+            }
+        }
+        controls.dead_zone = controls.dead_zone.clamp(0., 0.95);
+        controls
+    }
+
+    pub fn save(&self) {
+        let result = ron::ser::to_string_pretty(self, Default::default())
+            .map_err(|err| err.to_string())
+            .and_then(|text| fs::write(CONTROLS_FILE, text).map_err(|err| err.to_string()));
+        if let Err(err) = result {
+            warn!("failed to save {}: {}", CONTROLS_FILE, err);
+        }
+    }
+
+    pub fn bindings(&self, player: usize, action: Action) -> &[Binding] {
+        self.bindings
+            .get(player)
+            .and_then(|bindings| bindings.get(&action))
+            .map_or(&[], Vec::as_slice)
+    }
+
+    /// 同じ種類 (キーボード・ゲームパッド) の割り当てを置き換える
+    pub fn rebind(&mut self, player: usize, action: Action, binding: Binding) {
+        let bindings = self.bindings[player].entry(action).or_default();
+        bindings.retain(|old| old.is_gamepad() != binding.is_gamepad());
+        bindings.push(binding);
+        //画面の案内にはキーボードの割り当てを先に出す
+        bindings.sort_by_key(|binding| binding.is_gamepad());
+    }
+
+    pub fn reset(&mut self, player: usize, action: Action) {
+        self.bindings[player].insert(action, Self::default_bindings(player, action));
+    }
+}
+
+//プレイヤーごとのこのフレームの操作。画面の切り替え (ポーズ・タイトル) はこちらを読む
+#[derive(Default)]
+pub struct ActionState {
+    current: [u8; MAX_PLAYERS],
+    previous: [u8; MAX_PLAYERS],
+    //離すまで押していないことにする操作
+    consumed: [u8; MAX_PLAYERS],
+}
+
+impl ActionState {
+    pub fn pressed(&self, player: usize, action: Action) -> bool {
+        self.current[player] & action as u8 != 0
+    }
+
+    pub fn just_pressed(&self, player: usize, action: Action) -> bool {
+        self.pressed(player, action) && self.previous[player] & action as u8 == 0
+    }
+
+    /// action を押したプレイヤー。同時に押した場合は番号の小さい方
+    pub fn just_pressed_by(&self, action: Action) -> Option<usize> {
+        (0..MAX_PLAYERS).find(|&player| self.just_pressed(player, action))
+    }
+
+    /// 画面を切り替えたボタンで、次の画面の操作 (弾を撃つなど) をしないようにする
+    pub fn consume(&mut self, action: Action) {
+        for player in 0..MAX_PLAYERS {
+            self.consumed[player] |= action as u8;
+            self.current[player] &= !(action as u8);
+        }
+    }
+
+    fn update(&mut self, bits: [u8; MAX_PLAYERS]) {
+        self.previous = self.current;
+        for (player, bits) in bits.into_iter().enumerate() {
+            self.consumed[player] &= bits;
+            self.current[player] = bits & !self.consumed[player];
+        }
+    }
+}
+
+//1 tick 分の全員の入力。リプレイではこのビット列を記録・再生する
+#[derive(Default)]
+pub struct PlayerInput {
+    current: [u8; MAX_PLAYERS],
+    previous: [u8; MAX_PLAYERS],
+}
+
+impl PlayerInput {
+    pub fn bits(&self) -> u16 {
+        pack_inputs(self.current)
+    }
+
+    pub fn push(&mut self, bits: u16) {
+        self.previous = self.current;
+        self.current = unpack_inputs(bits);
+    }
+
+    pub fn pressed(&self, player: usize, action: Action) -> bool {
+        self.current[player] & action as u8 != 0
+    }
+
+    pub fn just_pressed(&self, player: usize, action: Action) -> bool {
+        self.pressed(player, action) && self.previous[player] & action as u8 == 0
+    }
+}
+
+//入力をキーボード・ゲームパッドから読むか、リプレイから読むか
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum InputSource {
+    #[default]
+    Devices,
+    Replay,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub struct InputLabel;
+
+pub struct PlayerInputPlugin;
+
+impl Plugin for PlayerInputPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.world.contains_resource::<Controls>() {
+            app.insert_resource(Controls::load());
+        }
+        app.insert_resource(PlayerInput::default())
+            .insert_resource(ActionState::default())
+            .init_resource::<InputSource>()
+            .add_system_to_stage(CoreStage::PreUpdate, action_state_system.after(InputSystem))
+            .add_sim_system(
+                SimStage::PreUpdate,
+                player_input_system.label(InputLabel).after(SimLabel),
+            );
+    }
+}
+
+/// 割り当てに従ってキーボードと、各プレイヤーのゲームパッドを読む
+fn action_state_system(
+    kb: Res<Input<KeyCode>>,
+    gamepads: Res<Gamepads>,
+    buttons: Res<Input<GamepadButton>>,
+    axes: Res<Axis<GamepadAxis>>,
+    controls: Res<Controls>,
+    mut actions: ResMut<ActionState>,
+) {
+    let mut pads: Vec<Gamepad> = gamepads.iter().copied().collect();
+    pads.sort_by_key(|gamepad| gamepad.0);
+
+    let mut bits = [0; MAX_PLAYERS];
+    for (player, bits) in bits.iter_mut().enumerate() {
+        let pad = pads.get(player).copied();
+        let active = |binding: &Binding| match *binding {
+            Binding::Key(key) => kb.pressed(key),
+            Binding::Button(button) => {
+                pad.is_some_and(|pad| buttons.pressed(GamepadButton(pad, button)))
+            }
+            Binding::Axis(axis, direction) => pad.is_some_and(|pad| {
+                let value = axes.get(GamepadAxis(pad, axis)).unwrap_or(0.);
+                match direction {
+                    AxisDirection::Positive => value > controls.dead_zone,
+                    AxisDirection::Negative => value < -controls.dead_zone,
+                }
+            }),
+        };
+
+        for action in Action::ALL {
+            if controls.bindings(player, action).iter().any(active) {
+                *bits |= action as u8;
+            }
+        }
+    }
+    actions.update(bits);
+}
+
+fn player_input_system(
+    actions: Res<ActionState>,
+    source: Res<InputSource>,
+    mut input: ResMut<PlayerInput>,
+) {
+    if *source != InputSource::Devices {
+        return;
+    }
+    input.push(pack_inputs(actions.current.map(|bits| bits & SIM_ACTIONS)));
+}
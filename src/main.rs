@@ -2,156 +2,337 @@
 
 use bevy::math::Vec3Swizzles;
 use bevy::prelude::*;
-use bevy::sprite::collide_aabb::collide;
+use cli::LaunchOptions;
+use collision::{CollisionPlugin, DamageEvent};
+use config::{ConfigPlugin, ConfigWatcher, GameConfig};
+use controls_menu::ControlsMenuPlugin;
+use display::{DisplayPlugin, WindowSettings};
+use clock::{ClockPlugin, SimAppExt, SimStage, SimTime};
 use components::{
-    Enemy, Explosion, ExplosionTimer, ExplosionToSpawn, FromEnemy, FromPlayer, Laser, Movable,
-    Player, SpriteSize, Velocity,
+    Bounded, Enemy, Explosion, ExplosionTimer, ExplosionToSpawn, HitFlash, Laser, Movable, Player,
+    PlayerId, Points, Velocity,
 };
+use enemy::boss::BossTypes;
+use enemy::formation::Formation;
+use enemy::types::EnemyTypes;
 use enemy::EnemyPlugin;
+use hud::HudPlugin;
+use input::PlayerInputPlugin;
+use pickup::PickupPlugin;
 use player::PlayerPlugin;
-use std::collections::HashSet;
-
+use replay::{Replay, ReplayMode, ReplayPlugin};
+use rng::{RngPlugin, SeedSetting};
+use score::{FormationKills, Score, ScorePlugin};
+use state::{StatePlugin, UiFont};
+
+mod cli;
+mod clock;
+mod collision;
 mod components;
+mod config;
+mod controls_menu;
+mod display;
 mod enemy;
+mod headless;
+mod hud;
+mod input;
+mod pickup;
 mod player;
+mod replay;
+mod rng;
+mod score;
+mod state;
 
 
-const PLAYER_SPRITE: &str = "Player.png";
-const PLAYER_SIZE: (f32, f32) = (1.5, 0.78125);
-const PLAYER_LASER_SPRITE: &str = "laser.png";
-const PLAYER_LASER_SIZE: (f32, f32) = (9., 54.);
-
-const ENEMY_SPRITE: &str = "Enemy.png";
-const ENEMY_SIZE: (f32, f32) = (1.5, 0.78125);
-const ENEMY_LASER_SPRITE: &str = "Flame_01.png";
-const ENEMY_LASER_SIZE: (f32, f32) = (9., 54.);
-
-const EXPLOSION_SHEET: &str = "Explosion.png";
 const EXPLOSION_LEN: usize = 16;
 
-const SPRITE_SCALE: f32 = 0.5;
+const FONT: &str = "fonts/FiraSans-Bold.ttf";
 
 const TIME_STEP: f32 = 1. / 60.;
-const BASE_SPEED: f32 = 500.;
 
-const PLAYER_RESPAWN_DELAY: f64 = 2.;
-const ENEMY_MAX: u32 = 2;
-const FORMATION_MEMBERS_MAX: u32 = 2;
+//被弾した敵の色
+const HIT_FLASH_COLOR: Color = Color::rgb(1., 0.3, 0.3);
 
+//ウィンドウの実際の大きさ。ウィンドウの大きさが変わると更新する
 pub struct WinSize {
     pub w: f32,
     pub h: f32,
 }
 
+//ゲームロジックで使う画面の大きさ。ウィンドウの大きさに関係なく一定で、カメラで拡大・縮小して表示する
+pub struct Playfield {
+    pub w: f32,
+    pub h: f32,
+}
+
+impl Playfield {
+    pub fn from_config(config: &GameConfig) -> Self {
+        Self {
+            w: config.window.width,
+            h: config.window.height,
+        }
+    }
+}
+
+//ヘッドレスモードではダミーのハンドルを使う
+#[derive(Default)]
 struct GameTextures {
     player: Handle<Image>,
     player_laser: Handle<Image>,
-    enemy: Handle<Image>,
     enemy_laser: Handle<Image>,
     explosion: Handle<TextureAtlas>,
 }
 
 struct EnemyCount(u32);
 
+//最後の残機がやられた時に送る
+pub struct GameOverEvent;
+
+//敵・ボスのパーツを倒した時に、その位置を送る
+pub struct EnemyDestroyed(pub Vec3);
+
+//同時に遊べる人数
+pub const MAX_PLAYERS: usize = 2;
+
+//次に始めるゲームの人数。タイトル画面で選ぶ
+pub struct PlayerCount(pub usize);
+
 struct PlayerState {
     on: bool,
     //生きているか
     last_shot: f64, //
+    //残機
+    lives: u32,
+    //次に残機が増えるスコアのインデックス
+    next_extra_life: usize,
+    //ボムの残り
+    bombs: u32,
 }
 
-impl Default for PlayerState {
-    fn default() -> Self {
+impl PlayerState {
+    pub fn new(lives: u32, bombs: u32) -> Self {
         Self {
             on: false,
             last_shot: -1.,
+            lives,
+            next_extra_life: 0,
+            bombs,
         }
     }
-}
-
-impl PlayerState {
     pub fn shot(&mut self, time: f64) {
         self.on = false;
         self.last_shot = time;
+        self.lives = self.lives.saturating_sub(1);
     }
     pub fn spawned(&mut self) {
         self.on = true;
         self.last_shot = -1.;
     }
+    pub fn is_game_over(&self) -> bool {
+        !self.on && self.lives == 0
+    }
+}
+
+//プレイヤーごとの状態。PlayerId の番号で引く。参加していないプレイヤーは残機 0 で復活しない
+struct Players([PlayerState; MAX_PLAYERS]);
+
+impl Players {
+    pub fn new(count: usize, lives: u32, bombs: u32) -> Self {
+        Self(std::array::from_fn(|id| {
+            if id < count {
+                PlayerState::new(lives, bombs)
+            } else {
+                PlayerState::new(0, 0)
+            }
+        }))
+    }
+
+    /// 全員の残機がなくなったらゲームオーバー
+    pub fn is_game_over(&self) -> bool {
+        self.0.iter().all(PlayerState::is_game_over)
+    }
+
+    /// 全員の残機の合計
+    pub fn lives(&self) -> u32 {
+        self.0.iter().map(|player| player.lives).sum()
+    }
+}
+
+//1 tick のゲームロジックの実行順。リプレイを再現できるように順番を固定する
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub enum GameSystem {
+    Input,
+    Fire,
+    Movement,
+    Collision,
+    Spawn,
 }
 
 fn main() {
-    App::new()
+    let mut options = LaunchOptions::from_args();
+    let config = GameConfig::load_or_default(&options.config);
+    let replay_mode = match replay_mode(&mut options, &config) {
+        Ok(mode) => mode,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    if options.headless {
+        headless::run(&options, config, replay_mode);
+        return;
+    }
+
+    let window_settings = WindowSettings::load();
+
+    let mut app = App::new();
+    //リプレイには設定を保存しないので、記録・再生中は設定ファイルを読み込み直さない
+    if matches!(replay_mode, ReplayMode::Off) {
+        app.insert_resource(ConfigWatcher::new(options.config.clone()));
+    }
+    app.insert_resource(replay_mode)
         .insert_resource(ClearColor(Color::rgb(0.04, 0.04, 0.04)))
-        .insert_resource(WindowDescriptor {
-            title: "Rust Invaders".to_string(),
-            width: 598.0,
-            height: 676.0,
-            ..Default::default()
-        })
+        .insert_resource(window_settings.descriptor(&config))
+        .insert_resource(window_settings)
+        .insert_resource(Playfield::from_config(&config))
+        .insert_resource(config)
+        .insert_resource(SeedSetting(options.seed))
+        .insert_resource(PlayerCount(options.players))
         .add_plugins(DefaultPlugins)
-        .add_plugin(PlayerPlugin)
-        .add_plugin(EnemyPlugin)
+        .add_plugin(GamePlugin)
+        .add_plugin(DisplayPlugin)
         .add_startup_system(setup_system)
-        .add_system(movable_system)
-        .add_system(player_laser_hit_enemy_system)
-        .add_system(enemy_laser_hit_player_system)
-        .add_system(explosion_to_spawn_system)
-        .add_system(explosion_animation_system)
         .run();
 }
 
+//リプレイを再生する場合は記録されたシードを使う
+fn replay_mode(options: &mut LaunchOptions, config: &GameConfig) -> Result<ReplayMode, String> {
+    if let Some(path) = &options.replay {
+        let replay = Replay::load(path)
+            .map_err(|err| format!("failed to load replay {}: {}", path.display(), err))?;
+        //設定が違うと途中でずれるだけなので、再生を始める前に止める
+        if matches!(replay.config_hash, Some(hash) if hash != replay::config_hash(config)) {
+            return Err(format!(
+                "cannot play replay {}: config changed since it was recorded ({} or the enemy, wave and boss data files)",
+                path.display(),
+                options.config.display()
+            ));
+        }
+        options.seed = Some(replay.seed);
+        options.players = replay.player_count;
+        Ok(ReplayMode::Playback {
+            replay,
+            cursor: 0,
+            status: replay::PlaybackStatus::Running,
+        })
+    } else if let Some(path) = &options.record {
+        Ok(ReplayMode::Record {
+            path: path.clone(),
+            config_hash: replay::config_hash(config),
+            inputs: Vec::new(),
+        })
+    } else {
+        Ok(ReplayMode::Off)
+    }
+}
+
+//ゲームロジック一式。ウィンドウ・アセットに依存するものは setup_system で用意する
+pub struct GamePlugin;
+
+impl Plugin for GamePlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(EnemyCount(0))
+            .add_plugin(ClockPlugin)
+            .add_plugin(CollisionPlugin)
+            .add_plugin(ConfigPlugin)
+            .add_plugin(RngPlugin)
+            .add_plugin(PlayerInputPlugin)
+            .add_plugin(ReplayPlugin)
+            .add_plugin(StatePlugin)
+            .add_plugin(ControlsMenuPlugin)
+            .add_plugin(ScorePlugin)
+            .add_plugin(PlayerPlugin)
+            .add_plugin(EnemyPlugin)
+            .add_plugin(PickupPlugin)
+            .add_plugin(HudPlugin)
+            .add_sim_system_set(
+                SimStage::Update,
+                SystemSet::new()
+                    .label(GameSystem::Movement)
+                    .after(GameSystem::Fire)
+                    .with_system(movable_system)
+                    .with_system(playfield_bounds_system.after(movable_system)),
+            )
+            .add_sim_system_set(
+                SimStage::Update,
+                SystemSet::new()
+                    .label(GameSystem::Collision)
+                    .after(GameSystem::Movement)
+                    .with_system(enemy_damage_system)
+                    .with_system(player_damage_system),
+            )
+            .add_sim_system(SimStage::Update, explosion_to_spawn_system)
+            .add_sim_system(SimStage::Update, explosion_animation_system);
+    }
+}
+
 fn setup_system(mut commands: Commands,
+                config: Res<GameConfig>,
                 asset_server: Res<AssetServer>,
                 mut texture_atlases: ResMut<Assets<TextureAtlas>>,
-                mut windows: ResMut<Windows>,
+                windows: Res<Windows>,
+                mut enemy_types: ResMut<EnemyTypes>,
+                mut boss_types: ResMut<BossTypes>,
 ) {
     //カメラ
     commands.spawn_bundle(OrthographicCameraBundle::new_2d());
+    commands.spawn_bundle(UiCameraBundle::default());
 
     //ウィンドウサイズ
-    let window = windows.get_primary_mut().unwrap();
+    let window = windows.get_primary().unwrap();
     let (win_w, win_h) = (window.width(), window.height());
 
-    //ウィンドウの位置
-    window.set_position(IVec2::new(1900, 0));
-
     //Windowsサイズのリソースを追加
     let win_size = WinSize { w: win_w, h: win_h };
     commands.insert_resource(win_size);
 
     //爆発テクスチャ生成
-    let texture_handle = asset_server.load(EXPLOSION_SHEET);
+    let texture_handle = asset_server.load(config.sprites.explosion_sheet.as_str());
     let texture_atlas = TextureAtlas::from_grid(texture_handle, Vec2::new(32., 32.), 4, 4);
     let explosion = texture_atlases.add(texture_atlas);
 
     //ゲームテクスチャのリソースを追加
     let game_textures = GameTextures {
-        player: asset_server.load(PLAYER_SPRITE),
-        player_laser: asset_server.load(PLAYER_LASER_SPRITE),
-        enemy: asset_server.load(ENEMY_SPRITE),
-        enemy_laser: asset_server.load(ENEMY_LASER_SPRITE),
+        player: asset_server.load(config.sprites.player.as_str()),
+        player_laser: asset_server.load(config.sprites.player_laser.as_str()),
+        enemy_laser: asset_server.load(config.sprites.enemy_laser.as_str()),
         explosion,
     };
     commands.insert_resource(game_textures);
-    commands.insert_resource(EnemyCount(0));
+    enemy_types.load_textures(&asset_server);
+    boss_types.load_textures(&asset_server);
+    commands.insert_resource(UiFont(asset_server.load(FONT)));
 }
 
 fn movable_system(
     mut commands: Commands,
-    win_size: Res<WinSize>,
+    sim_time: Res<SimTime>,
+    config: Res<GameConfig>,
+    playfield: Res<Playfield>,
     mut query: Query<(Entity, &Velocity, &mut Transform, &Movable)>) {
     for (entity, velocity, mut transform, movable) in query.iter_mut() {
         let translation = &mut transform.translation;
-        translation.x += velocity.x * TIME_STEP * BASE_SPEED;
-        translation.y += velocity.y * TIME_STEP * BASE_SPEED;
+        translation.x += velocity.x * sim_time.delta_seconds() * config.base_speed;
+        translation.y += velocity.y * sim_time.delta_seconds() * config.base_speed;
 
         if movable.auto_despawn {
             //画面外にlaserが出た時にそれを削除する
             const MARGIN: f32 = 200.;
-            if translation.y > win_size.h / 2. + MARGIN
-                || translation.y < -win_size.h / 2. - MARGIN
-                || translation.x > win_size.w / 2. + MARGIN
-                || translation.x < -win_size.w / 2. - MARGIN
+            if translation.y > playfield.h / 2. + MARGIN
+                || translation.y < -playfield.h / 2. - MARGIN
+                || translation.x > playfield.w / 2. + MARGIN
+                || translation.x < -playfield.w / 2. - MARGIN
             {
                 commands.entity(entity).despawn();
             }
@@ -159,92 +340,124 @@ fn movable_system(
     }
 }
 
+/// プレイヤーが動ける範囲 (最小, 最大)。プレイフィールドから設定の余白を除いたもの
+fn playfield_rect(playfield: &Playfield, config: &GameConfig) -> (Vec2, Vec2) {
+    let margins = &config.playfield;
+    (
+        Vec2::new(
+            -playfield.w / 2. + margins.margin_left,
+            -playfield.h / 2. + margins.margin_bottom,
+        ),
+        Vec2::new(
+            playfield.w / 2. - margins.margin_right,
+            playfield.h / 2. - margins.margin_top,
+        ),
+    )
+}
 
-fn player_laser_hit_enemy_system(
-    mut commands: Commands,
-    mut enemy_count: ResMut<EnemyCount>,
-    laser_query: Query<(Entity, &Transform, &SpriteSize), (With<Laser>, With<FromPlayer>)>,
-    enemy_query: Query<(Entity, &Transform, &SpriteSize), With<Enemy>>,
+//プレイフィールドの端で止める。端に向かう速度は 0 にする
+fn playfield_bounds_system(
+    config: Res<GameConfig>,
+    playfield: Res<Playfield>,
+    mut query: Query<(&Bounded, &mut Transform, Option<&mut Velocity>)>,
 ) {
-    let mut despawned_entities: HashSet<Entity> = HashSet::new();
-    //レーザーを繰り返して出す
-    for (laser_entity, laser_tf, laser_size) in laser_query.iter() {
-        if despawned_entities.contains(&laser_entity) {
+    let (min, max) = playfield_rect(&playfield, &config);
+    for (bounded, mut transform, velocity) in query.iter_mut() {
+        //プレイフィールドより大きい場合は中央に置く
+        let center = (min + max) / 2.;
+        let (min, max) = ((min + bounded.0).min(center), (max - bounded.0).max(center));
+        let position = transform.translation.truncate();
+        let clamped = position.clamp(min, max);
+        if clamped == position {
             continue;
         }
+        transform.translation.x = clamped.x;
+        transform.translation.y = clamped.y;
 
-        let laser_scale = Vec2::from(laser_tf.scale.xy());
-
-        //繰り返し敵を出す
-        for (enemy_entity, enemy_tf, enemy_size) in enemy_query.iter() {
-            if despawned_entities.contains(&enemy_entity) ||
-                despawned_entities.contains(&laser_entity) {
-                continue;
+        if let Some(mut velocity) = velocity {
+            if (clamped.x < position.x && velocity.x > 0.) || (clamped.x > position.x && velocity.x < 0.) {
+                velocity.x = 0.;
             }
-            let enemy_scale = Vec2::from(enemy_tf.scale.xy());
-
-            //当たり判定の設定
-            let collision = collide(
-                laser_tf.translation,
-                laser_size.0 * laser_scale,
-                enemy_tf.translation,
-                enemy_size.0 * enemy_scale * 100.,
-            );
-
-            //当たり判定による行動
-            if let Some(_) = collision {
-                //敵を削除する
-                commands.entity(enemy_entity).despawn();
-                despawned_entities.insert(enemy_entity);
-                enemy_count.0 -= 1;
-
-                //レーザーの削除
-                commands.entity(laser_entity).despawn();
-                despawned_entities.insert(laser_entity);
-
-                //爆発エフェクトをスポーンさせる
-                commands.spawn().insert(ExplosionToSpawn(enemy_tf.translation.clone()));
+            if (clamped.y < position.y && velocity.y > 0.) || (clamped.y > position.y && velocity.y < 0.) {
+                velocity.y = 0.;
             }
         }
     }
 }
 
-fn enemy_laser_hit_player_system(
+type EnemyHitQuery<'a> = (&'a Transform, &'a Points, &'a Formation, &'a mut Sprite);
+
+//敵がダメージを受けた時の処理。点数はプレイヤーかプレイヤーの弾で倒した時だけ入る
+#[allow(clippy::too_many_arguments)]
+fn enemy_damage_system(
     mut commands: Commands,
-    mut player_state: ResMut<PlayerState>,
-    time: Res<Time>,
-    laser_query: Query<(Entity, &Transform, &SpriteSize), (With<Laser>, With<FromEnemy>)>,
-    player_query: Query<(Entity, &Transform, &SpriteSize), With<Player>>,
+    config: Res<GameConfig>,
+    mut enemy_count: ResMut<EnemyCount>,
+    mut score: ResMut<Score>,
+    mut formation_kills: ResMut<FormationKills>,
+    mut damage_events: EventReader<DamageEvent>,
+    mut destroyed_events: EventWriter<EnemyDestroyed>,
+    source_query: Query<&PlayerId>,
+    mut enemy_query: Query<EnemyHitQuery, With<Enemy>>,
 ) {
-    if let Ok((player_entity, player_tf, player_size)) = player_query.get_single() {
-        let player_scale = Vec2::from(player_tf.scale.xy());
-
-        for (laser_entity, laser_tf, laser_size) in laser_query.iter() {
-            let laser_scale = Vec2::from(laser_tf.scale.xy());
+    for event in damage_events.iter() {
+        let (enemy_tf, points, formation, mut sprite) = match enemy_query.get_mut(event.target) {
+            Ok(enemy) => enemy,
+            Err(_) => continue,
+        };
+
+        //耐久力が残っている場合は点滅させる
+        if !event.killed {
+            sprite.color = HIT_FLASH_COLOR;
+            commands.entity(event.target).insert(HitFlash::default());
+            continue;
+        }
 
-            // determine if collision
-            let collision = collide(
-                laser_tf.translation,
-                laser_size.0 * laser_scale,
-                player_tf.translation,
-                player_size.0 * player_scale * 100.,
-            );
+        //敵を削除する
+        commands.entity(event.target).despawn();
+        enemy_count.0 -= 1;
 
-            // perform the collision
-            if let Some(_) = collision {
-                // remove the player
-                commands.entity(player_entity).despawn();
-                player_state.shot(time.seconds_since_startup());
+        //倒したプレイヤー (体当たりか、そのプレイヤーの弾) に点数を入れる
+        if let Ok(&PlayerId(player)) = source_query.get(event.source) {
+            score.0[player] += points.0;
 
-                // remove the laser
-                commands.entity(laser_entity).despawn();
+            //フォーメーションを全滅させたらボーナス
+            if formation_kills.register(formation.id, formation.members) {
+                score.0[player] += config.enemy.formation_bonus;
+            }
+        }
 
-                // spawn the explosionToSpawn
-                commands.spawn().insert(ExplosionToSpawn(player_tf.translation.clone()));
+        //爆発エフェクトをスポーンさせる
+        commands.spawn().insert(ExplosionToSpawn(enemy_tf.translation));
+        destroyed_events.send(EnemyDestroyed(enemy_tf.translation));
+    }
+}
 
-                break;
-            }
+//プレイヤーが倒された時の処理。シールドと無敵は damage_system で処理済み
+fn player_damage_system(
+    mut commands: Commands,
+    mut players: ResMut<Players>,
+    mut game_over_events: EventWriter<GameOverEvent>,
+    mut damage_events: EventReader<DamageEvent>,
+    sim_time: Res<SimTime>,
+    player_query: Query<(&Transform, &PlayerId), With<Player>>,
+) {
+    for event in damage_events.iter().filter(|event| event.killed) {
+        let (player_tf, id) = match player_query.get(event.target) {
+            Ok(player) => player,
+            Err(_) => continue,
+        };
+
+        // remove the player
+        commands.entity(event.target).despawn();
+        players.0[id.0].shot(sim_time.seconds());
+        //もう1人が残っている間はゲームを続ける
+        if players.is_game_over() {
+            game_over_events.send(GameOverEvent);
         }
+
+        // spawn the explosionToSpawn
+        commands.spawn().insert(ExplosionToSpawn(player_tf.translation));
     }
 }
 
@@ -274,11 +487,11 @@ fn explosion_to_spawn_system(
 
 fn explosion_animation_system(
     mut commands: Commands,
-    time: Res<Time>,
+    sim_time: Res<SimTime>,
     mut query: Query<(Entity, &mut ExplosionTimer, &mut TextureAtlasSprite), With<Explosion>>,
 ) {
     for (entity, mut timer, mut sprite) in query.iter_mut() {
-        timer.0.tick(time.delta());
+        timer.0.tick(sim_time.delta());
         if timer.0.finished() {
             sprite.index += 1;
             if sprite.index >= EXPLOSION_LEN {
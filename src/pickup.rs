@@ -0,0 +1,243 @@
+use crate::clock::{seconds_to_ticks, SimAppExt, SimStage, SimTime};
+use crate::components::{
+    BaseColor, Invulnerable, Movable, Pickup, Player, PlayerId, PowerUp, Shield, SpeedBoost,
+    Velocity, Weapon,
+};
+use crate::collision::{Collider, CollisionEvent, CollisionLayers, Layer, Shape};
+use crate::config::{GameConfig, PickupWeights};
+use crate::rng::GameRng;
+use crate::{EnemyDestroyed, GameSystem, Players};
+use bevy::prelude::*;
+use rand::Rng;
+use std::collections::HashSet;
+
+//シールドを持っている時のプレイヤーの色
+const SHIELD_COLOR: Color = Color::rgb(0.5, 0.9, 1.);
+
+pub struct PickupPlugin;
+
+impl Plugin for PickupPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<EnemyDestroyed>()
+            .add_sim_system_set(
+                SimStage::Update,
+                SystemSet::new()
+                    .label(GameSystem::Collision)
+                    .after(GameSystem::Movement)
+                    //同じ tick で被弾した時も残機が増えてから判定する
+                    .with_system(pickup_collect_system.before(crate::player_damage_system)),
+            )
+            .add_sim_system_set(
+                SimStage::Update,
+                SystemSet::new()
+                    //乱数を使う順番を固定する
+                    .after(GameSystem::Collision)
+                    .before(GameSystem::Spawn)
+                    .with_system(pickup_drop_system.after(crate::player::player_bomb_system)),
+            )
+            .add_sim_system_set(
+                SimStage::Update,
+                SystemSet::new()
+                    .with_system(power_up_expire_system)
+                    .with_system(shield_color_system),
+            );
+    }
+}
+
+impl PowerUp {
+    /// 重みに従って種類を決める
+    fn choose(weights: &PickupWeights, rng: &mut impl Rng) -> Self {
+        let mut roll = rng.gen_range(0..weights.total());
+        for (power_up, weight) in [
+            (PowerUp::Weapon, weights.weapon),
+            (PowerUp::Shield, weights.shield),
+            (PowerUp::Life, weights.life),
+            (PowerUp::Speed, weights.speed),
+            (PowerUp::Bomb, weights.bomb),
+        ] {
+            if roll < weight {
+                return power_up;
+            }
+            roll -= weight;
+        }
+        PowerUp::Bomb
+    }
+
+    fn color(self) -> Color {
+        match self {
+            PowerUp::Weapon => Color::rgb(1., 0.6, 0.1),
+            PowerUp::Shield => SHIELD_COLOR,
+            PowerUp::Life => Color::rgb(0.3, 1., 0.3),
+            PowerUp::Speed => Color::rgb(1., 1., 0.3),
+            PowerUp::Bomb => Color::rgb(1., 0.3, 0.3),
+        }
+    }
+}
+
+//倒した敵の位置に確率でパワーアップを落とす
+fn pickup_drop_system(
+    mut commands: Commands,
+    config: Res<GameConfig>,
+    mut rng: ResMut<GameRng>,
+    mut events: EventReader<EnemyDestroyed>,
+) {
+    for EnemyDestroyed(position) in events.iter() {
+        if !rng.gen_bool(config.pickup.drop_chance) {
+            continue;
+        }
+        let power_up = PowerUp::choose(&config.pickup.weights, &mut *rng);
+        commands
+            .spawn_bundle(SpriteBundle {
+                sprite: Sprite {
+                    color: power_up.color(),
+                    custom_size: Some(Vec2::from(config.pickup.size)),
+                    ..Default::default()
+                },
+                transform: Transform::from_translation(Vec3::new(position.x, position.y, 5.)),
+                ..Default::default()
+            })
+            .insert(Pickup(power_up))
+            .insert(Collider(Shape::Aabb(config.pickup.size.0, config.pickup.size.1)))
+            .insert(CollisionLayers::new(Layer::Pickup, &config.collision.pickup))
+            .insert(Movable { auto_despawn: true })
+            .insert(Velocity {
+                x: 0.,
+                y: -config.pickup.fall_speed,
+            });
+    }
+}
+
+type PlayerPowerUpQuery<'a> = (
+    &'a PlayerId,
+    &'a CollisionLayers,
+    &'a mut Weapon,
+    Option<&'a Invulnerable>,
+);
+
+fn pickup_collect_system(
+    mut commands: Commands,
+    config: Res<GameConfig>,
+    sim_time: Res<SimTime>,
+    mut players: ResMut<Players>,
+    mut collision_events: EventReader<CollisionEvent>,
+    mut player_query: Query<PlayerPowerUpQuery, With<Player>>,
+    pickup_query: Query<(&Pickup, &CollisionLayers)>,
+) {
+    let now = sim_time.tick();
+    //despawn は tick の終わりまで遅れるので、2機が同じ tick に触れても1回だけ取る
+    let mut collected = HashSet::new();
+
+    for event in collision_events.iter() {
+        let (pickup_entity, player_entity) =
+            match event.matching(|e| pickup_query.contains(e), |e| player_query.contains(e)) {
+                Some(pair) => pair,
+                None => continue,
+            };
+        let ((pickup, pickup_layers), (&PlayerId(id), player_layers, mut weapon, invulnerable)) =
+            match (pickup_query.get(pickup_entity), player_query.get_mut(player_entity)) {
+                (Ok(pickup), Ok(player)) => (pickup, player),
+                _ => continue,
+            };
+        if !pickup_layers.hits(player_layers) || !collected.insert(pickup_entity) {
+            continue;
+        }
+        commands.entity(pickup_entity).despawn();
+
+        match pickup.0 {
+            PowerUp::Weapon => weapon.tier = weapon.tier.upgraded(),
+            PowerUp::Shield if config.pickup.shield_hits > 0 => {
+                commands.entity(player_entity).insert(Shield {
+                    hits: config.pickup.shield_hits,
+                });
+            }
+            PowerUp::Shield => {
+                let until = now + seconds_to_ticks(config.pickup.shield_duration);
+                commands
+                    .entity(player_entity)
+                    .insert(Invulnerable::extended(invulnerable, until));
+            }
+            PowerUp::Life => players.0[id].lives += 1,
+            PowerUp::Speed => {
+                commands.entity(player_entity).insert(SpeedBoost {
+                    multiplier: config.pickup.speed_multiplier,
+                    until: now + seconds_to_ticks(config.pickup.speed_duration),
+                });
+            }
+            PowerUp::Bomb => players.0[id].bombs += 1,
+        }
+    }
+}
+
+//時間が切れたスピードアップを外す
+fn power_up_expire_system(
+    mut commands: Commands,
+    sim_time: Res<SimTime>,
+    boost_query: Query<(Entity, &SpeedBoost)>,
+) {
+    let now = sim_time.tick();
+    for (entity, boost) in boost_query.iter() {
+        if now >= boost.until {
+            commands.entity(entity).remove::<SpeedBoost>();
+        }
+    }
+}
+
+//シールドを持っている間はプレイヤーの色を変える
+type ShieldColorQuery<'a> = (&'a mut Sprite, Option<&'a Shield>, Option<&'a BaseColor>);
+
+fn shield_color_system(mut query: Query<ShieldColorQuery, With<Player>>) {
+    for (mut sprite, shield, base_color) in query.iter_mut() {
+        let color = match (shield, base_color) {
+            (Some(_), _) => SHIELD_COLOR,
+            (None, Some(base_color)) => base_color.0,
+            (None, None) => Color::WHITE,
+        };
+        if sprite.color != color {
+            sprite.color = color;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::ecs::event::Events;
+
+    #[test]
+    fn two_players_touching_one_pickup_collect_it_once() {
+        let mut world = World::new();
+        world.insert_resource(GameConfig::default());
+        world.insert_resource(SimTime::default());
+        world.insert_resource(Players::new(2, 3, 0));
+        world.insert_resource(Events::<CollisionEvent>::default());
+
+        let ships: Vec<Entity> = (0..2)
+            .map(|id| {
+                world
+                    .spawn()
+                    .insert(Player)
+                    .insert(PlayerId(id))
+                    .insert(CollisionLayers::new(Layer::Player, &[]))
+                    .insert(Weapon::default())
+                    .id()
+            })
+            .collect();
+        let pickup = world
+            .spawn()
+            .insert(Pickup(PowerUp::Life))
+            .insert(CollisionLayers::new(Layer::Pickup, &[Layer::Player]))
+            .id();
+        for &ship in &ships {
+            world
+                .resource_mut::<Events<CollisionEvent>>()
+                .send(CollisionEvent { a: pickup, b: ship });
+        }
+
+        let mut stage = SystemStage::single(pickup_collect_system);
+        stage.run(&mut world);
+
+        let players = world.resource::<Players>();
+        assert_eq!(players.0[0].lives + players.0[1].lives, 7);
+        assert!(world.get_entity(pickup).is_none());
+    }
+}
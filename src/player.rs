@@ -1,102 +1,332 @@
-use bevy::core::FixedTimestep;
-use crate::{GameTextures, WinSize, PLAYER_SIZE, SPRITE_SCALE, TIME_STEP, BASE_SPEED, PLAYER_LASER_SIZE, Laser, PlayerState, PLAYER_RESPAWN_DELAY};
+use crate::{
+    GameTextures, Playfield, Laser, PlayerCount, Players, GameOverEvent, GameSystem,
+    MAX_PLAYERS,
+};
+use crate::collision::{Collider, CollisionLayers, CollisionStep, DirectDamage, Layer, Shape};
+use crate::config::GameConfig;
+use crate::clock::{seconds_to_ticks, sim_every, SimAppExt, SimStage, SimTime};
+use crate::input::{Action, PlayerInput};
+use crate::score::Score;
 use bevy::prelude::*;
-use crate::components::{FromEnemy, FromPlayer, Movable, Player, SpriteSize, Velocity};
+use crate::components::{
+    BaseColor, BossPart, Bounded, Damage, Enemy, ExplosionToSpawn, Health, Invulnerable, Movable,
+    Piercing, Player, PlayerId, SpeedBoost, Velocity, Weapon, WeaponTier,
+};
+use std::f32::consts::TAU;
+
+//3方向に撃つ時の左右のレーザーの角度
+const TRIPLE_SPREAD: f32 = 0.25;
+
+//ボムを使った時にプレイヤーの周りに出す爆発の数と半径
+const BOMB_EXPLOSIONS: u32 = 8;
+const BOMB_RADIUS: f32 = 120.;
+
+//無敵中の点滅の間隔 (tick)
+const BLINK_TICKS: u64 = 4;
+
+//プレイヤーの機体の色 (1P, 2P)
+const PLAYER_COLORS: [Color; MAX_PLAYERS] = [Color::WHITE, Color::rgb(0.6, 1., 0.6)];
 
 pub struct PlayerPlugin;
 
 impl Plugin for PlayerPlugin {
     fn build(&self, app: &mut App) {
-        app.insert_resource(PlayerState::default())
-            .add_system_set(
+        if !app.world.contains_resource::<PlayerCount>() {
+            app.insert_resource(PlayerCount(1));
+        }
+        //残機はゲーム開始時に設定の値で初期化する
+        app.insert_resource(Players::new(0, 0, 0))
+            .add_event::<GameOverEvent>()
+            .add_sim_system_set(
+            SimStage::Update,
             SystemSet::new()
-                .with_run_criteria(FixedTimestep::step(0.5))
+                .with_run_criteria(sim_every(0.5))
+                .label(GameSystem::Spawn)
+                .after(GameSystem::Collision)
                 .with_system(player_spawn_system),
         )
-            .add_system(player_keyboard_event_system)
-            .add_system(player_fire_system);
+            .add_sim_system_set(
+                SimStage::Update,
+                SystemSet::new()
+                    .with_system(player_keyboard_event_system.label(GameSystem::Input))
+                    .with_system(
+                        player_fire_system
+                            .label(GameSystem::Fire)
+                            .after(GameSystem::Input),
+                    )
+                    .with_system(
+                        player_extra_life_system
+                            .label(GameSystem::Spawn)
+                            .after(GameSystem::Collision),
+                    )
+                    .with_system(player_invulnerable_system),
+            )
+            //ボムのダメージは同じ tick の当たり判定の後に damage_system で与える
+            .add_sim_system(
+                SimStage::Update,
+                player_bomb_system
+                    .after(CollisionStep::Propagate)
+                    .before(CollisionStep::Damage),
+            );
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn player_spawn_system(
     mut commands: Commands,
-    mut player_state: ResMut<PlayerState>,
-    time: Res<Time>,
+    mut players: ResMut<Players>,
+    player_count: Res<PlayerCount>,
+    sim_time: Res<SimTime>,
+    config: Res<GameConfig>,
     game_textures: Res<GameTextures>,
-    win_size: Res<WinSize>,
+    playfield: Res<Playfield>,
 ) {
-    let now = time.seconds_since_startup();
-    let last_shot = player_state.last_shot;
+    let now = sim_time.seconds();
+
+    for (id, &color) in PLAYER_COLORS.iter().enumerate() {
+        let player_state = &players.0[id];
+        let last_shot = player_state.last_shot;
 
-    if !player_state.on && (last_shot == -1. || now > last_shot + PLAYER_RESPAWN_DELAY) {
+        //残機がない場合は復活しない
+        if player_state.lives == 0 {
+            continue;
+        }
+        if player_state.on || (last_shot != -1. && now <= last_shot + config.player.respawn_delay) {
+            continue;
+        }
 
-        //プレイヤーの追加
-        let bottom = -win_size.h / 2.;
+        //プレイヤーの追加。2人の時は左右に分けて出す
+        let scale = config.sprites.scale;
+        let x = ((id as f32 + 0.5) / player_count.0 as f32 - 0.5) * playfield.w;
+        let bottom = -playfield.h / 2.;
         commands.spawn_bundle(SpriteBundle {
+            sprite: Sprite {
+                color,
+                ..Default::default()
+            },
             texture: game_textures.player.clone(),
             transform: Transform {
-                translation: Vec3::new(0., bottom + PLAYER_SIZE.1 / 2. * SPRITE_SCALE + 50., 10.),
-                scale: Vec3::new(SPRITE_SCALE, SPRITE_SCALE, 1.),
+                translation: Vec3::new(x, bottom + config.player.size.1 / 2. * scale + 50., 10.),
+                scale: Vec3::new(scale, scale, 1.),
                 ..Default::default()
             },
             ..Default::default()
         })
             .insert(Player)
-            .insert(SpriteSize::from(PLAYER_SIZE))
+            .insert(PlayerId(id))
+            .insert(BaseColor(color))
+            //復活した直後は無敵にする
+            .insert(Invulnerable {
+                until: sim_time.tick() + seconds_to_ticks(config.player.respawn_invulnerable),
+            })
+            .insert(Weapon::default())
+            .insert(Collider::sprite_box(config.player.size))
+            .insert(CollisionLayers::new(Layer::Player, &config.collision.player))
+            .insert(Health(1))
+            .insert(Damage(1))
             .insert(Movable { auto_despawn: false })
-            .insert(Velocity { x: 1., y: 0. });
+            .insert(Bounded(Vec2::new(config.player.size.0, config.player.size.1) * 100. * scale / 2.))
+            .insert(Velocity { x: 0., y: 0. });
 
-        player_state.spawned();
+        players.0[id].spawned();
     }
 }
 
 fn player_fire_system(
     mut commands: Commands,
-    kb: Res<Input<KeyCode>>,
+    input: Res<PlayerInput>,
+    sim_time: Res<SimTime>,
+    config: Res<GameConfig>,
     game_textures: Res<GameTextures>,
-    query: Query<&Transform, With<Player>>,
+    mut query: Query<(&PlayerId, &Transform, &mut Weapon), With<Player>>,
 ) {
-    if let Ok(player_tf) = query.get_single() {
-        if kb.just_pressed(KeyCode::Space) {
-            let (x, y) = (player_tf.translation.x, player_tf.translation.y);
-            let y_offset = PLAYER_SIZE.0 / 1.
-                * SPRITE_SCALE + 75.;
-            let x_offset = 10.;
-
-            let mut spawn_laser = |x_offset: f32| {
-                commands.spawn_bundle(SpriteBundle {
-                    texture: game_textures.player_laser.clone(),
-                    transform: Transform {
-                        translation: Vec3::new(x + x_offset, y + y_offset, 0.),
-                        scale: Vec3::new(SPRITE_SCALE, SPRITE_SCALE, 1.),
-                        ..Default::default()
-                    },
-                    ..Default::default()
-                })
-                    .insert(Laser)
-                    .insert(FromPlayer)
-                    .insert(SpriteSize::from(PLAYER_LASER_SIZE))
-                    .insert(Movable { auto_despawn: true })
-                    .insert(Velocity { x: 0., y: 1. });
-            };
-
-            spawn_laser(x_offset);
-            spawn_laser(-x_offset);
+    for (&PlayerId(id), player_tf, mut weapon) in query.iter_mut() {
+        //連射しない設定の時は押し直すまで撃たない
+        let trigger = if config.player.autofire {
+            input.pressed(id, Action::Fire)
+        } else {
+            input.just_pressed(id, Action::Fire)
+        };
+        let now = sim_time.tick();
+        if !trigger || now < weapon.next_fire {
+            continue;
+        }
+        weapon.next_fire = now + seconds_to_ticks(config.player.fire_interval);
+
+        let (x, y) = (player_tf.translation.x, player_tf.translation.y);
+        let scale = config.sprites.scale;
+        let y_offset = config.player.size.0 / 1.
+            * scale + 75.;
+
+        //角度は真上からのずれ (ラジアン)
+        let mut spawn_laser = |x_offset: f32, angle: f32, piercing: bool| {
+            //ビームは縦に長くする
+            let y_scale = if piercing { scale * 3. } else { scale };
+            let mut laser = commands.spawn_bundle(SpriteBundle {
+                texture: game_textures.player_laser.clone(),
+                transform: Transform {
+                    translation: Vec3::new(x + x_offset, y + y_offset, 0.),
+                    rotation: Quat::from_rotation_z(-angle),
+                    scale: Vec3::new(scale, y_scale, 1.),
+                },
+                ..Default::default()
+            });
+            laser
+                .insert(Laser)
+                .insert(PlayerId(id))
+                .insert(Collider(Shape::Obb(config.player.laser_size.0, config.player.laser_size.1)))
+                .insert(CollisionLayers::new(Layer::PlayerShot, &config.collision.player_shot))
+                .insert(Damage(1))
+                //敵の弾と撃ち合った時は1発で消える
+                .insert(Health(1))
+                .insert(Movable { auto_despawn: true })
+                .insert(Velocity { x: angle.sin(), y: angle.cos() });
+            if piercing {
+                laser.insert(Piercing::default());
+            }
+        };
+
+        match weapon.tier {
+            WeaponTier::Single => spawn_laser(0., 0., false),
+            WeaponTier::Twin => {
+                spawn_laser(10., 0., false);
+                spawn_laser(-10., 0., false);
+            }
+            WeaponTier::Triple => {
+                spawn_laser(0., 0., false);
+                spawn_laser(10., TRIPLE_SPREAD, false);
+                spawn_laser(-10., -TRIPLE_SPREAD, false);
+            }
+            WeaponTier::Beam => spawn_laser(0., 0., true),
         }
     }
 }
 
+//押している向きの速さに向けて少しずつ加速・減速する
 fn player_keyboard_event_system(
-    kb: Res<Input<KeyCode>>,
-    mut query: Query<&mut Velocity, With<Player>>,
+    input: Res<PlayerInput>,
+    sim_time: Res<SimTime>,
+    config: Res<GameConfig>,
+    mut query: Query<(&PlayerId, &mut Velocity, Option<&SpeedBoost>), With<Player>>,
 ) {
-    if let Ok(mut velocity) = query.get_single_mut() {
-        velocity.x = if kb.pressed(KeyCode::Left) {
-            -1.
-        } else if kb.pressed(KeyCode::Right) {
-            1.
+    for (&PlayerId(id), mut velocity, boost) in query.iter_mut() {
+        let mut speed = boost.map_or(1., |boost| boost.multiplier);
+        if input.pressed(id, Action::Focus) {
+            speed *= config.player.focus_multiplier;
+        }
+        let target = if input.pressed(id, Action::MoveLeft) {
+            -speed
+        } else if input.pressed(id, Action::MoveRight) {
+            speed
         } else {
             0.
+        };
+
+        //止まる時・遅くなる時・向きを変える時は減速の値を使う
+        let rate = if target.abs() < velocity.x.abs() || target * velocity.x < 0. {
+            config.player.deceleration
+        } else {
+            config.player.acceleration
+        };
+        let max_change = rate * sim_time.delta_seconds();
+        velocity.x += (target - velocity.x).clamp(-max_change, max_change);
+    }
+}
+
+fn player_extra_life_system(
+    score: Res<Score>,
+    config: Res<GameConfig>,
+    mut players: ResMut<Players>,
+) {
+    //自分のスコアが閾値を超えたら残機を増やす。残機がなくなったプレイヤーは増えない
+    for id in 0..MAX_PLAYERS {
+        if players.0[id].is_game_over() {
+            continue;
+        }
+        while let Some(&threshold) = config.player.extra_life_scores.get(players.0[id].next_extra_life) {
+            if score.0[id] < threshold {
+                break;
+            }
+            let player_state = &mut players.0[id];
+            player_state.lives += 1;
+            player_state.next_extra_life += 1;
         }
     }
-}
\ No newline at end of file
+}
+
+type PlayerBombQuery<'a> = (Entity, &'a PlayerId, &'a Transform, Option<&'a Invulnerable>);
+
+type BombTargetFilter = (Or<(With<Enemy>, With<BossPart>)>, Without<Player>);
+
+/// ボムで画面内の敵の弾を全て消し、画面内の敵とボスのパーツにダメージを与える。
+/// ダメージは当たり判定と同じく damage_system で処理し、倒した敵の点数はボムを使ったプレイヤーに入る
+#[allow(clippy::too_many_arguments)]
+pub fn player_bomb_system(
+    mut commands: Commands,
+    input: Res<PlayerInput>,
+    sim_time: Res<SimTime>,
+    config: Res<GameConfig>,
+    playfield: Res<Playfield>,
+    mut players: ResMut<Players>,
+    mut damage_events: EventWriter<DirectDamage>,
+    player_query: Query<PlayerBombQuery, With<Player>>,
+    laser_query: Query<(Entity, &Transform, &CollisionLayers), With<Laser>>,
+    target_query: Query<(Entity, &GlobalTransform), BombTargetFilter>,
+) {
+    let on_screen = |pos: Vec3| pos.x.abs() <= playfield.w / 2. && pos.y.abs() <= playfield.h / 2.;
+
+    for (player_entity, &PlayerId(id), player_tf, invulnerable) in player_query.iter() {
+        //やられた直後は使えない
+        let player_state = &players.0[id];
+        if !input.just_pressed(id, Action::Bomb) || !player_state.on || player_state.bombs == 0 {
+            continue;
+        }
+        players.0[id].bombs -= 1;
+
+        //ボムが終わるまで敵の弾に当たらない
+        let until = sim_time.tick() + seconds_to_ticks(config.player.bomb_invulnerable);
+        commands
+            .entity(player_entity)
+            .insert(Invulnerable::extended(invulnerable, until));
+
+        for (laser_entity, laser_tf, layers) in laser_query.iter() {
+            if layers.is(Layer::EnemyShot) && on_screen(laser_tf.translation) {
+                commands.entity(laser_entity).despawn();
+            }
+        }
+
+        for (target, target_tf) in target_query.iter() {
+            if on_screen(target_tf.translation) {
+                damage_events.send(DirectDamage {
+                    target,
+                    source: player_entity,
+                    amount: config.player.bomb_damage,
+                });
+            }
+        }
+
+        //プレイヤーの周りに爆発を広げる
+        for i in 0..BOMB_EXPLOSIONS {
+            let angle = TAU * i as f32 / BOMB_EXPLOSIONS as f32;
+            let offset = Vec3::new(angle.cos(), angle.sin(), 0.) * BOMB_RADIUS;
+            commands.spawn().insert(ExplosionToSpawn(player_tf.translation + offset));
+        }
+    }
+}
+
+//無敵の間は点滅させ、時間が切れたら外す
+fn player_invulnerable_system(
+    mut commands: Commands,
+    sim_time: Res<SimTime>,
+    mut query: Query<(Entity, &Invulnerable, &mut Visibility)>,
+) {
+    let now = sim_time.tick();
+    for (entity, invulnerable, mut visibility) in query.iter_mut() {
+        if now >= invulnerable.until {
+            visibility.is_visible = true;
+            commands.entity(entity).remove::<Invulnerable>();
+        } else {
+            visibility.is_visible = ((invulnerable.until - now) / BLINK_TICKS).is_multiple_of(2);
+        }
+    }
+}
@@ -0,0 +1,548 @@
+use crate::clock::{SimAppExt, SimStage, SimTime};
+use crate::components::{Enemy, Laser, Player};
+use crate::config::GameConfig;
+use crate::enemy::boss::BOSS_TYPES_FILE;
+use crate::enemy::types::ENEMY_TYPES_FILE;
+use crate::enemy::wave::WAVES_FILE;
+use crate::input::{InputLabel, InputSource, PlayerInput};
+use crate::rng::GameRng;
+use crate::score::Score;
+use crate::state::AppState;
+use crate::{PlayerCount, Players, MAX_PLAYERS, TIME_STEP};
+use bevy::prelude::*;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 4] = b"RIRP";
+//3: 設定のハッシュを記録する。2: 人数と2人分の入力を記録する。1 のファイルは1人用として読む
+const VERSION: u8 = 3;
+//ファイルの末尾にある SimSnapshot の長さ
+const SNAPSHOT_LEN: usize = 8 + 4 * 5 + 8;
+//読み込める tick 数の上限 (24時間分)。壊れたファイルで巨大な入力を確保しないようにする
+const MAX_TICKS: u64 = (24. * 60. * 60. / TIME_STEP) as u64;
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+//ゲームの状態の要約。リプレイが記録通りに再現できたかの検証に使う
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SimSnapshot {
+    pub tick: u64,
+    //全員の合計
+    pub score: u32,
+    pub lives: u32,
+    pub players: u32,
+    pub enemies: u32,
+    pub lasers: u32,
+    //プレイヤー・敵・レーザーの座標のハッシュ
+    pub checksum: u64,
+}
+
+//最後に進めた tick の状態
+#[derive(Default)]
+pub struct LastSnapshot(pub SimSnapshot);
+
+//シードと人数と tick ごとの入力 (PlayerInput::bits)
+pub struct Replay {
+    pub seed: u64,
+    pub player_count: usize,
+    //記録した時の config_hash。バージョン 2 以前のファイルにはない
+    pub config_hash: Option<u64>,
+    pub inputs: Vec<u16>,
+    pub result: SimSnapshot,
+}
+
+impl Replay {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::decode(&fs::read(path)?)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.encode())
+    }
+
+    //入力は (ビット, 連続 tick 数) のランレングスで保存する
+    fn encode(&self) -> Vec<u8> {
+        let mut runs: Vec<(u16, u32)> = Vec::new();
+        for &bits in &self.inputs {
+            match runs.last_mut() {
+                Some((last, len)) if *last == bits && *len < u32::MAX => *len += 1,
+                _ => runs.push((bits, 1)),
+            }
+        }
+
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        out.extend_from_slice(&self.seed.to_le_bytes());
+        out.push(self.player_count as u8);
+        out.extend_from_slice(&self.config_hash.unwrap_or_default().to_le_bytes());
+        out.extend_from_slice(&(runs.len() as u32).to_le_bytes());
+        for (bits, len) in runs {
+            out.extend_from_slice(&bits.to_le_bytes());
+            out.extend_from_slice(&len.to_le_bytes());
+        }
+
+        let result = &self.result;
+        out.extend_from_slice(&result.tick.to_le_bytes());
+        for value in [result.score, result.lives, result.players, result.enemies, result.lasers] {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        out.extend_from_slice(&result.checksum.to_le_bytes());
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> io::Result<Self> {
+        //入力は最後の tick の分までしか保存しないので、先に末尾の結果を読んで入力の数の上限にする
+        let body_len = bytes
+            .len()
+            .checked_sub(SNAPSHOT_LEN)
+            .ok_or_else(|| invalid_data("unexpected end of replay file"))?;
+        let (body, tail) = bytes.split_at(body_len);
+        let result = ByteReader(tail).snapshot()?;
+        if result.tick > MAX_TICKS {
+            return Err(invalid_data("replay is too long"));
+        }
+
+        let mut reader = ByteReader(body);
+        if reader.take(4)? != MAGIC {
+            return Err(invalid_data("not a replay file"));
+        }
+        let version = reader.u8()?;
+        if !(1..=VERSION).contains(&version) {
+            return Err(invalid_data("unsupported replay version"));
+        }
+
+        let seed = reader.u64()?;
+        let player_count = if version == 1 { 1 } else { reader.u8()? as usize };
+        if player_count == 0 || player_count > MAX_PLAYERS {
+            return Err(invalid_data("invalid player count"));
+        }
+        let config_hash = if version >= 3 { Some(reader.u64()?) } else { None };
+        let run_count = reader.u32()?;
+        let mut inputs = Vec::new();
+        let mut total: u64 = 0;
+        for _ in 0..run_count {
+            let bits = if version == 1 {
+                reader.u8()? as u16
+            } else {
+                reader.u16()?
+            };
+            let len = reader.u32()?;
+            total = total
+                .checked_add(len as u64)
+                .filter(|&total| total <= result.tick)
+                .ok_or_else(|| invalid_data("replay has more inputs than ticks"))?;
+            inputs.resize(total as usize, bits);
+        }
+        if !reader.0.is_empty() {
+            return Err(invalid_data("unexpected data in replay file"));
+        }
+
+        Ok(Self {
+            seed,
+            player_count,
+            config_hash,
+            inputs,
+            result,
+        })
+    }
+}
+
+struct ByteReader<'a>(&'a [u8]);
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(invalid_data("unexpected end of replay file"));
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn snapshot(&mut self) -> io::Result<SimSnapshot> {
+        Ok(SimSnapshot {
+            tick: self.u64()?,
+            score: self.u32()?,
+            lives: self.u32()?,
+            players: self.u32()?,
+            enemies: self.u32()?,
+            lasers: self.u32()?,
+            checksum: self.u64()?,
+        })
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+//FNV-1a。実行ごと・環境ごとに同じ値になるハッシュ
+fn fnv1a(hash: u64, bytes: impl IntoIterator<Item = u8>) -> u64 {
+    bytes
+        .into_iter()
+        .fold(hash, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// 読み込んだ設定と敵・ウェーブ・ボスのデータファイルのハッシュ。記録時と再生時で違えば同じ結果にならない
+pub fn config_hash(config: &GameConfig) -> u64 {
+    //ファイルがない・壊れている時はデフォルト値を使うので、読み込んだ後の値をハッシュする
+    let text = ron::to_string(config).unwrap_or_default();
+    let mut hash = fnv1a(FNV_OFFSET, text.into_bytes());
+    for path in [ENEMY_TYPES_FILE, WAVES_FILE, BOSS_TYPES_FILE] {
+        //ない時もある時と区別する
+        let data = fs::read(path).ok();
+        hash = fnv1a(hash, [data.is_some() as u8]);
+        hash = fnv1a(hash, data.unwrap_or_default());
+    }
+    hash
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaybackStatus {
+    Running,
+    Matched,
+    Mismatched,
+}
+
+pub enum ReplayMode {
+    Off,
+    Record {
+        path: PathBuf,
+        config_hash: u64,
+        inputs: Vec<u16>,
+    },
+    Playback {
+        replay: Replay,
+        cursor: usize,
+        status: PlaybackStatus,
+    },
+}
+
+impl ReplayMode {
+    pub fn playback_status(&self) -> Option<PlaybackStatus> {
+        match self {
+            ReplayMode::Playback { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+}
+
+pub struct ReplayPlugin;
+
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.world.contains_resource::<ReplayMode>() {
+            app.insert_resource(ReplayMode::Off);
+        }
+        let is_playback = matches!(
+            app.world.get_resource::<ReplayMode>(),
+            Some(ReplayMode::Playback { .. })
+        );
+        if is_playback {
+            app.insert_resource(InputSource::Replay);
+        }
+
+        app.insert_resource(LastSnapshot::default())
+            .add_sim_system(SimStage::PreUpdate, replay_input_system.after(InputLabel))
+            .add_sim_system_set(
+                SimStage::PostUpdate,
+                SystemSet::new()
+                    .with_system(snapshot_system)
+                    .with_system(playback_verify_system.after(snapshot_system)),
+            )
+            .add_system_set(SystemSet::on_enter(AppState::Playing).with_system(replay_start_system))
+            .add_system_set(SystemSet::on_exit(AppState::Playing).with_system(replay_finish_system));
+    }
+}
+
+//入力の記録、または記録された入力の再生
+fn replay_input_system(mut mode: ResMut<ReplayMode>, mut input: ResMut<PlayerInput>) {
+    match &mut *mode {
+        ReplayMode::Off => {}
+        ReplayMode::Record { inputs, .. } => inputs.push(input.bits()),
+        ReplayMode::Playback { replay, cursor, .. } => {
+            input.push(replay.inputs.get(*cursor).copied().unwrap_or(0));
+            *cursor += 1;
+        }
+    }
+}
+
+type SnapshotFilter = Or<(With<Player>, With<Enemy>, With<Laser>)>;
+
+fn snapshot_system(
+    sim_time: Res<SimTime>,
+    score: Res<Score>,
+    players: Res<Players>,
+    query: Query<(&Transform, Option<&Player>, Option<&Enemy>), SnapshotFilter>,
+    mut last: ResMut<LastSnapshot>,
+) {
+    let mut snapshot = SimSnapshot {
+        tick: sim_time.tick(),
+        score: score.total(),
+        lives: players.lives(),
+        ..Default::default()
+    };
+
+    //エンティティごとに FNV-1a でハッシュを計算し、順序に依存しないように足し合わせる
+    let mut checksum: u64 = 0;
+    for (tf, player, enemy) in query.iter() {
+        let kind = match (player, enemy) {
+            (Some(_), _) => {
+                snapshot.players += 1;
+                0u8
+            }
+            (_, Some(_)) => {
+                snapshot.enemies += 1;
+                1
+            }
+            _ => {
+                snapshot.lasers += 1;
+                2
+            }
+        };
+        let x = (tf.translation.x * 100.).round() as i64;
+        let y = (tf.translation.y * 100.).round() as i64;
+        let bytes = [kind].into_iter().chain(x.to_le_bytes()).chain(y.to_le_bytes());
+        checksum = checksum.wrapping_add(fnv1a(FNV_OFFSET, bytes));
+    }
+    snapshot.checksum = checksum;
+
+    last.0 = snapshot;
+}
+
+//記録された最後の tick に到達したら結果を比較する
+fn playback_verify_system(last: Res<LastSnapshot>, mut mode: ResMut<ReplayMode>) {
+    if let ReplayMode::Playback { replay, status, .. } = &mut *mode {
+        if *status == PlaybackStatus::Running && last.0.tick == replay.result.tick {
+            *status = if last.0 == replay.result {
+                info!("replay matched: {:?}", last.0);
+                PlaybackStatus::Matched
+            } else {
+                error!("replay mismatch: expected {:?}, got {:?}", replay.result, last.0);
+                PlaybackStatus::Mismatched
+            };
+        }
+    }
+}
+
+fn replay_start_system(mut mode: ResMut<ReplayMode>) {
+    match &mut *mode {
+        ReplayMode::Off => {}
+        ReplayMode::Record { inputs, .. } => inputs.clear(),
+        ReplayMode::Playback { cursor, status, .. } => {
+            *cursor = 0;
+            *status = PlaybackStatus::Running;
+        }
+    }
+}
+
+fn save_recording(mode: &ReplayMode, seed: u64, player_count: usize, result: SimSnapshot) {
+    if let ReplayMode::Record {
+        path,
+        config_hash,
+        inputs,
+    } = mode
+    {
+        let replay = Replay {
+            seed,
+            player_count,
+            config_hash: Some(*config_hash),
+            inputs: inputs[..(result.tick as usize).min(inputs.len())].to_vec(),
+            result,
+        };
+        match replay.save(path) {
+            Ok(()) => info!("replay saved to {}", path.display()),
+            Err(err) => error!("failed to save replay {}: {}", path.display(), err),
+        }
+    }
+}
+
+/// ゲームオーバー前に終了する場合 (ヘッドレスの時間切れなど) に記録を保存する
+pub fn flush_recording(world: &mut World) {
+    let seed = world.resource::<GameRng>().seed();
+    let player_count = world.resource::<PlayerCount>().0;
+    let result = world.resource::<LastSnapshot>().0;
+    save_recording(world.resource::<ReplayMode>(), seed, player_count, result);
+}
+
+fn replay_finish_system(
+    rng: Res<GameRng>,
+    player_count: Res<PlayerCount>,
+    last: Res<LastSnapshot>,
+    mut mode: ResMut<ReplayMode>,
+) {
+    save_recording(&mode, rng.seed(), player_count.0, last.0);
+
+    match &mut *mode {
+        ReplayMode::Off | ReplayMode::Record { .. } => {}
+        ReplayMode::Playback { replay, status, .. } => {
+            //記録より早くゲームが終わった
+            if *status == PlaybackStatus::Running {
+                error!(
+                    "replay ended at tick {} before the recorded tick {}",
+                    last.0.tick, replay.result.tick
+                );
+                *status = PlaybackStatus::Mismatched;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replay(player_count: usize, inputs: Vec<u16>) -> Replay {
+        Replay {
+            seed: 0x0123_4567_89ab_cdef,
+            player_count,
+            config_hash: Some(0x1111_2222_3333_4444),
+            inputs,
+            result: SimSnapshot {
+                tick: 1234,
+                score: 5600,
+                lives: 2,
+                players: 1,
+                enemies: 7,
+                lasers: 3,
+                checksum: 0xdead_beef_cafe_f00d,
+            },
+        }
+    }
+
+    fn assert_same(a: &Replay, b: &Replay) {
+        assert_eq!(a.seed, b.seed);
+        assert_eq!(a.player_count, b.player_count);
+        assert_eq!(a.config_hash, b.config_hash);
+        assert_eq!(a.inputs, b.inputs);
+        assert_eq!(a.result, b.result);
+    }
+
+    //バージョン 1 の形式 (1人用、入力は u8) で書く
+    fn encode_v1(seed: u64, runs: &[(u8, u32)], result: &SimSnapshot) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.push(1);
+        out.extend_from_slice(&seed.to_le_bytes());
+        out.extend_from_slice(&(runs.len() as u32).to_le_bytes());
+        for &(bits, len) in runs {
+            out.push(bits);
+            out.extend_from_slice(&len.to_le_bytes());
+        }
+        out.extend_from_slice(&result.tick.to_le_bytes());
+        for value in [result.score, result.lives, result.players, result.enemies, result.lasers] {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        out.extend_from_slice(&result.checksum.to_le_bytes());
+        out
+    }
+
+    #[test]
+    fn round_trip() {
+        let inputs = vec![0, 0, 5, 5, 5, 0x0401, 0xffff, 0, 0x0401];
+        let original = replay(2, inputs);
+        let decoded = Replay::decode(&original.encode()).unwrap();
+        assert_same(&original, &decoded);
+    }
+
+    #[test]
+    fn round_trip_without_inputs() {
+        let original = replay(1, Vec::new());
+        let decoded = Replay::decode(&original.encode()).unwrap();
+        assert_same(&original, &decoded);
+    }
+
+    #[test]
+    fn repeated_inputs_are_run_length_encoded() {
+        let original = replay(1, vec![4; 1000]);
+        let encoded = original.encode();
+        let empty = replay(1, Vec::new()).encode();
+        //1つのランは入力 2 byte + 長さ 4 byte
+        assert_eq!(encoded.len(), empty.len() + 6);
+        assert_same(&original, &Replay::decode(&encoded).unwrap());
+    }
+
+    #[test]
+    fn decodes_version_1_as_one_player() {
+        let result = replay(1, Vec::new()).result;
+        let bytes = encode_v1(42, &[(0, 3), (0x15, 2), (4, 1)], &result);
+        let decoded = Replay::decode(&bytes).unwrap();
+        assert_eq!(decoded.seed, 42);
+        assert_eq!(decoded.player_count, 1);
+        assert_eq!(decoded.config_hash, None);
+        assert_eq!(decoded.inputs, vec![0, 0, 0, 0x15, 0x15, 4]);
+        assert_eq!(decoded.result, result);
+    }
+
+    #[test]
+    fn config_hash_follows_the_config() {
+        let config = GameConfig::default();
+        assert_eq!(config_hash(&config), config_hash(&config.clone()));
+        let mut changed = config.clone();
+        changed.player.lives += 1;
+        assert_ne!(config_hash(&config), config_hash(&changed));
+    }
+
+    #[test]
+    fn rejects_wrong_magic() {
+        let mut bytes = replay(1, vec![1]).encode();
+        bytes[0] = b'X';
+        assert!(Replay::decode(&bytes).is_err());
+    }
+
+    #[test]
+    fn rejects_unsupported_version() {
+        let mut bytes = replay(1, vec![1]).encode();
+        bytes[4] = VERSION + 1;
+        assert!(Replay::decode(&bytes).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_player_count() {
+        for count in [0, MAX_PLAYERS + 1] {
+            let bytes = replay(count, vec![1]).encode();
+            assert!(Replay::decode(&bytes).is_err(), "player count {}", count);
+        }
+    }
+
+    #[test]
+    fn rejects_more_inputs_than_ticks() {
+        let result = replay(1, Vec::new()).result;
+        let bytes = encode_v1(0, &[(0, result.tick as u32 + 1)], &result);
+        assert!(Replay::decode(&bytes).is_err());
+
+        //巨大な長さや tick が書かれていても確保する前に弾く
+        let mut result = result;
+        result.tick = MAX_TICKS;
+        let bytes = encode_v1(0, &[(0, u32::MAX); 3], &result);
+        assert!(Replay::decode(&bytes).is_err());
+        result.tick = u64::MAX;
+        let bytes = encode_v1(0, &[(0, 1)], &result);
+        assert!(Replay::decode(&bytes).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_file() {
+        let bytes = replay(2, vec![1, 2, 3]).encode();
+        for len in 0..bytes.len() {
+            assert!(Replay::decode(&bytes[..len]).is_err(), "length {}", len);
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use crate::state::AppState;
+use bevy::prelude::*;
+use rand::rngs::StdRng;
+use rand::{thread_rng, Rng, RngCore, SeedableRng};
+
+//コマンドラインで指定されたシード。None の場合はゲームごとにランダムに決める
+#[derive(Default)]
+pub struct SeedSetting(pub Option<u64>);
+
+//敵の生成・フォーメーション・発射判定で使う乱数
+pub struct GameRng {
+    seed: u64,
+    rng: StdRng,
+}
+
+impl GameRng {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+}
+
+impl RngCore for GameRng {
+    fn next_u32(&mut self) -> u32 {
+        self.rng.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.rng.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.rng.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.rng.try_fill_bytes(dest)
+    }
+}
+
+pub struct RngPlugin;
+
+impl Plugin for RngPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SeedSetting>()
+            .insert_resource(GameRng::new(0))
+            .add_system_set(SystemSet::on_enter(AppState::Playing).with_system(rng_reseed_system));
+    }
+}
+
+//ゲーム開始ごとにシードを決めて記録する
+fn rng_reseed_system(setting: Res<SeedSetting>, mut rng: ResMut<GameRng>) {
+    let seed = setting.0.unwrap_or_else(|| thread_rng().gen());
+    *rng = GameRng::new(seed);
+    info!("run seed: {}", seed);
+}
@@ -0,0 +1,327 @@
+use crate::rng::GameRng;
+use crate::MAX_PLAYERS;
+use crate::state::{AppState, ScreenText, UiFont};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+
+const HIGH_SCORE_FILE: &str = "highscores.ron";
+const HIGH_SCORE_MAX: usize = 10;
+const INITIALS_LEN: usize = 3;
+
+//現在のスコア。PlayerId の番号ごとに数える
+#[derive(Default)]
+pub struct Score(pub [u32; MAX_PLAYERS]);
+
+impl Score {
+    /// 全員のスコアの合計。ハイスコアは2人で遊んだ時も合計で記録する
+    pub fn total(&self) -> u32 {
+        self.0.iter().sum()
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HighScoreEntry {
+    pub initials: String,
+    pub score: u32,
+    //そのゲームの乱数シード
+    #[serde(default)]
+    pub seed: u64,
+}
+
+//上位10件のハイスコア
+#[derive(Default, Serialize, Deserialize)]
+pub struct HighScores(pub Vec<HighScoreEntry>);
+
+impl HighScores {
+    pub fn load() -> Self {
+        match fs::read_to_string(HIGH_SCORE_FILE) {
+            Ok(text) => ron::from_str(&text).unwrap_or_else(|err| {
+                warn!("failed to parse {}: {}", HIGH_SCORE_FILE, err);
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+
+    pub fn save(&self) {
+        let result = ron::ser::to_string_pretty(self, Default::default())
+            .map_err(|err| err.to_string())
+            .and_then(|text| fs::write(HIGH_SCORE_FILE, text).map_err(|err| err.to_string()));
+        if let Err(err) = result {
+            warn!("failed to save {}: {}", HIGH_SCORE_FILE, err);
+        }
+    }
+
+    pub fn best(&self) -> u32 {
+        self.0.first().map_or(0, |entry| entry.score)
+    }
+
+    pub fn qualifies(&self, score: u32) -> bool {
+        score > 0 && (self.0.len() < HIGH_SCORE_MAX || self.0.iter().any(|e| score > e.score))
+    }
+
+    pub fn insert(&mut self, entry: HighScoreEntry) {
+        //同点の場合は先に記録された方を上にする
+        let index = self.0.iter().position(|e| entry.score > e.score).unwrap_or(self.0.len());
+        self.0.insert(index, entry);
+        self.0.truncate(HIGH_SCORE_MAX);
+    }
+}
+
+//フォーメーションごとの撃墜数
+#[derive(Default)]
+pub struct FormationKills(HashMap<u32, u32>);
+
+impl FormationKills {
+    /// 撃墜を記録して、フォーメーションが全滅したら true を返す
+    pub fn register(&mut self, formation_id: u32, members: u32) -> bool {
+        let kills = self.0.entry(formation_id).or_insert(0);
+        *kills += 1;
+        if *kills >= members {
+            self.0.remove(&formation_id);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+//イニシャル入力中の文字列
+#[derive(Default)]
+pub struct PendingInitials(pub Option<String>);
+
+#[derive(Component)]
+struct InitialsText;
+
+pub struct ScorePlugin;
+
+impl Plugin for ScorePlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Score::default())
+            .insert_resource(FormationKills::default())
+            .insert_resource(PendingInitials::default())
+            .insert_resource(HighScores::load())
+            .add_system_set(SystemSet::on_enter(AppState::Title).with_system(high_score_table_system))
+            .add_system_set(SystemSet::on_enter(AppState::Playing).with_system(score_reset_system))
+            .add_system_set(SystemSet::on_enter(AppState::GameOver).with_system(initials_enter_system))
+            .add_system_set(
+                SystemSet::on_update(AppState::GameOver)
+                    .with_system(initials_input_system)
+                    .with_system(initials_text_system),
+            );
+    }
+}
+
+fn bottom_text_bundle(font: &UiFont, value: String) -> TextBundle {
+    TextBundle {
+        style: Style {
+            position_type: PositionType::Absolute,
+            position: Rect {
+                bottom: Val::Px(40.),
+                left: Val::Px(40.),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        text: Text::with_section(
+            value,
+            TextStyle {
+                font: font.0.clone(),
+                font_size: 20.,
+                color: Color::WHITE,
+            },
+            Default::default(),
+        ),
+        ..Default::default()
+    }
+}
+
+//タイトル画面にハイスコア表を表示する
+fn high_score_table_system(mut commands: Commands, font: Res<UiFont>, high_scores: Res<HighScores>) {
+    if high_scores.0.is_empty() {
+        return;
+    }
+
+    let table = high_scores
+        .0
+        .iter()
+        .enumerate()
+        .map(|(i, e)| format!("{:>2}. {:<3} {:>8}", i + 1, e.initials, e.score))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    commands
+        .spawn_bundle(bottom_text_bundle(&font, format!("HIGH SCORES\n{}", table)))
+        .insert(ScreenText);
+}
+
+fn score_reset_system(mut score: ResMut<Score>, mut formation_kills: ResMut<FormationKills>) {
+    *score = Score::default();
+    formation_kills.clear();
+}
+
+//ハイスコアに入った場合はイニシャル入力を開始する
+fn initials_enter_system(
+    mut commands: Commands,
+    font: Res<UiFont>,
+    score: Res<Score>,
+    high_scores: Res<HighScores>,
+    mut pending: ResMut<PendingInitials>,
+) {
+    if !high_scores.qualifies(score.total()) {
+        return;
+    }
+
+    pending.0 = Some(String::new());
+    commands
+        .spawn_bundle(bottom_text_bundle(&font, String::new()))
+        .insert(InitialsText)
+        .insert(ScreenText);
+}
+
+fn initials_input_system(
+    mut chars: EventReader<ReceivedCharacter>,
+    mut kb: ResMut<Input<KeyCode>>,
+    score: Res<Score>,
+    rng: Res<GameRng>,
+    mut high_scores: ResMut<HighScores>,
+    mut pending: ResMut<PendingInitials>,
+) {
+    let initials = match pending.0.as_mut() {
+        Some(initials) => initials,
+        None => return,
+    };
+
+    for event in chars.iter() {
+        if event.char.is_ascii_alphanumeric() && initials.len() < INITIALS_LEN {
+            initials.push(event.char.to_ascii_uppercase());
+        }
+    }
+    if kb.just_pressed(KeyCode::Back) {
+        initials.pop();
+    }
+
+    if kb.just_pressed(KeyCode::Return) && !initials.is_empty() {
+        //確定したら保存する
+        kb.reset(KeyCode::Return);
+        high_scores.insert(HighScoreEntry {
+            initials: initials.clone(),
+            score: score.total(),
+            seed: rng.seed(),
+        });
+        high_scores.save();
+        pending.0 = None;
+    }
+}
+
+fn initials_text_system(
+    pending: Res<PendingInitials>,
+    mut query: Query<&mut Text, With<InitialsText>>,
+) {
+    if !pending.is_changed() {
+        return;
+    }
+
+    for mut text in query.iter_mut() {
+        text.sections[0].value = match &pending.0 {
+            Some(initials) => format!("NEW HIGH SCORE!\nEnter initials: {}_", initials),
+            None => "Saved!".to_string(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(initials: &str, score: u32) -> HighScoreEntry {
+        HighScoreEntry {
+            initials: initials.to_string(),
+            score,
+            seed: 0,
+        }
+    }
+
+    fn table(high_scores: &HighScores) -> Vec<(&str, u32)> {
+        high_scores
+            .0
+            .iter()
+            .map(|entry| (entry.initials.as_str(), entry.score))
+            .collect()
+    }
+
+    #[test]
+    fn insert_keeps_descending_order() {
+        let mut high_scores = HighScores::default();
+        high_scores.insert(entry("BBB", 200));
+        high_scores.insert(entry("AAA", 300));
+        high_scores.insert(entry("CCC", 100));
+        high_scores.insert(entry("DDD", 250));
+        assert_eq!(
+            table(&high_scores),
+            vec![("AAA", 300), ("DDD", 250), ("BBB", 200), ("CCC", 100)]
+        );
+        assert_eq!(high_scores.best(), 300);
+    }
+
+    #[test]
+    fn ties_keep_the_earlier_entry_above() {
+        let mut high_scores = HighScores::default();
+        high_scores.insert(entry("OLD", 100));
+        high_scores.insert(entry("NEW", 100));
+        assert_eq!(table(&high_scores), vec![("OLD", 100), ("NEW", 100)]);
+    }
+
+    #[test]
+    fn insert_truncates_to_the_maximum() {
+        let mut high_scores = HighScores::default();
+        for score in 1..=HIGH_SCORE_MAX as u32 {
+            high_scores.insert(entry("AAA", score * 10));
+        }
+        high_scores.insert(entry("TOP", 1000));
+        assert_eq!(high_scores.0.len(), HIGH_SCORE_MAX);
+        assert_eq!(high_scores.0[0].initials, "TOP");
+        //一番低いスコアが押し出される
+        assert_eq!(high_scores.0.last().unwrap().score, 20);
+
+        high_scores.insert(entry("LOW", 1));
+        assert_eq!(high_scores.0.len(), HIGH_SCORE_MAX);
+        assert!(high_scores.0.iter().all(|entry| entry.initials != "LOW"));
+    }
+
+    #[test]
+    fn qualifies_only_above_the_lowest_when_full() {
+        let mut high_scores = HighScores::default();
+        assert!(!high_scores.qualifies(0));
+        assert!(high_scores.qualifies(1));
+        for score in 1..=HIGH_SCORE_MAX as u32 {
+            high_scores.insert(entry("AAA", score * 10));
+        }
+        assert!(!high_scores.qualifies(10));
+        assert!(high_scores.qualifies(11));
+    }
+
+    #[test]
+    fn formation_bonus_after_all_members() {
+        let mut kills = FormationKills::default();
+        assert!(!kills.register(1, 3));
+        assert!(!kills.register(2, 2));
+        assert!(!kills.register(1, 3));
+        assert!(kills.register(1, 3));
+        assert!(kills.register(2, 2));
+    }
+
+    #[test]
+    fn total_adds_every_player() {
+        let mut score = Score::default();
+        score.0[0] = 1200;
+        score.0[MAX_PLAYERS - 1] += 300;
+        assert_eq!(score.total(), 1500);
+    }
+}
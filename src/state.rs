@@ -0,0 +1,229 @@
+use crate::components::{
+    Boss, BossPart, ChainExplosion, Enemy, Explosion, ExplosionToSpawn, Laser, Pickup, Player,
+};
+use crate::enemy::formation::FormationMaker;
+use crate::clock::{SimAppExt, SimStage, SimTime};
+use crate::score::PendingInitials;
+use crate::config::GameConfig;
+use crate::input::{Action, ActionState, Controls};
+use crate::replay::ReplayMode;
+use crate::{EnemyCount, GameOverEvent, GameSystem, PlayerCount, Players};
+use bevy::prelude::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AppState {
+    Title,
+    Playing,
+    Paused,
+    GameOver,
+    //操作の割り当てを変更する画面
+    Controls,
+}
+
+pub struct UiFont(pub Handle<Font>);
+
+//画面ごとのテキスト
+#[derive(Component)]
+pub struct ScreenText;
+
+pub struct StatePlugin;
+
+impl Plugin for StatePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_state(AppState::Title)
+            .add_system_set(SystemSet::on_enter(AppState::Title).with_system(title_enter_system))
+            .add_system_set(SystemSet::on_update(AppState::Title).with_system(title_input_system))
+            .add_system_set(SystemSet::on_exit(AppState::Title).with_system(screen_text_cleanup_system))
+            .add_system_set(
+                SystemSet::on_enter(AppState::Playing)
+                    .with_system(gameplay_cleanup_system)
+                    .with_system(gameplay_reset_system),
+            )
+            .add_system_set(SystemSet::on_update(AppState::Playing).with_system(pause_input_system))
+            .add_sim_system(SimStage::Update, game_over_event_system.after(GameSystem::Collision))
+            .add_system_set(SystemSet::on_exit(AppState::Playing).with_system(gameplay_cleanup_system))
+            .add_system_set(SystemSet::on_enter(AppState::Paused).with_system(paused_enter_system))
+            .add_system_set(SystemSet::on_update(AppState::Paused).with_system(resume_input_system))
+            .add_system_set(SystemSet::on_exit(AppState::Paused).with_system(screen_text_cleanup_system))
+            .add_system_set(SystemSet::on_enter(AppState::GameOver).with_system(game_over_enter_system))
+            .add_system_set(SystemSet::on_update(AppState::GameOver).with_system(game_over_input_system))
+            .add_system_set(SystemSet::on_exit(AppState::GameOver).with_system(screen_text_cleanup_system));
+    }
+}
+
+fn spawn_screen_text(commands: &mut Commands, font: &UiFont, title: &str, prompt: &str) {
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                size: Size::new(Val::Percent(100.), Val::Percent(100.)),
+                flex_direction: FlexDirection::ColumnReverse,
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                ..Default::default()
+            },
+            color: Color::NONE.into(),
+            ..Default::default()
+        })
+        .insert(ScreenText)
+        .with_children(|parent| {
+            parent.spawn_bundle(TextBundle {
+                text: Text::with_section(
+                    title,
+                    TextStyle {
+                        font: font.0.clone(),
+                        font_size: 48.,
+                        color: Color::WHITE,
+                    },
+                    Default::default(),
+                ),
+                ..Default::default()
+            });
+            parent.spawn_bundle(TextBundle {
+                text: Text::with_section(
+                    prompt,
+                    TextStyle {
+                        font: font.0.clone(),
+                        font_size: 24.,
+                        color: Color::GRAY,
+                    },
+                    Default::default(),
+                ),
+                ..Default::default()
+            });
+        });
+}
+
+//操作に割り当てた最初の入力の名前
+fn binding_label(controls: &Controls, player: usize, action: Action) -> String {
+    controls
+        .bindings(player, action)
+        .first()
+        .map_or_else(|| action.name().to_string(), |binding| binding.label())
+}
+
+fn title_enter_system(mut commands: Commands, font: Res<UiFont>, controls: Res<Controls>) {
+    let prompt = format!(
+        "{}: 1 player / {}: 2 players / C: controls",
+        binding_label(&controls, 0, Action::Fire),
+        binding_label(&controls, 1, Action::Fire)
+    );
+    spawn_screen_text(&mut commands, &font, "Rust Invaders", &prompt);
+}
+
+fn paused_enter_system(mut commands: Commands, font: Res<UiFont>, controls: Res<Controls>) {
+    let prompt = format!("Press {} to resume", binding_label(&controls, 0, Action::Pause));
+    spawn_screen_text(&mut commands, &font, "Paused", &prompt);
+}
+
+fn game_over_enter_system(mut commands: Commands, font: Res<UiFont>, controls: Res<Controls>) {
+    let prompt = format!(
+        "Press {} to return to title",
+        binding_label(&controls, 0, Action::Fire)
+    );
+    spawn_screen_text(&mut commands, &font, "Game Over", &prompt);
+}
+
+pub fn screen_text_cleanup_system(mut commands: Commands, query: Query<Entity, With<ScreenText>>) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+//1P の発射で1人、2P の発射で2人のゲームを始める。リプレイの再生中は記録された人数のまま
+fn title_input_system(
+    kb: Res<Input<KeyCode>>,
+    buttons: Res<Input<GamepadButton>>,
+    replay_mode: Res<ReplayMode>,
+    mut actions: ResMut<ActionState>,
+    mut player_count: ResMut<PlayerCount>,
+    mut state: ResMut<State<AppState>>,
+) {
+    if let Some(player) = actions.just_pressed_by(Action::Fire) {
+        if !matches!(*replay_mode, ReplayMode::Playback { .. }) {
+            player_count.0 = player + 1;
+        }
+        //同じフレームで発射されないようにリセットする
+        actions.consume(Action::Fire);
+        state.set(AppState::Playing).unwrap();
+    } else if kb.just_pressed(KeyCode::C)
+        || buttons
+            .get_just_pressed()
+            .any(|button| button.1 == GamepadButtonType::Select)
+    {
+        state.set(AppState::Controls).unwrap();
+    }
+}
+
+fn pause_input_system(mut actions: ResMut<ActionState>, mut state: ResMut<State<AppState>>) {
+    if actions.just_pressed_by(Action::Pause).is_some() {
+        actions.consume(Action::Pause);
+        state.push(AppState::Paused).unwrap();
+    }
+}
+
+fn resume_input_system(mut actions: ResMut<ActionState>, mut state: ResMut<State<AppState>>) {
+    if actions.just_pressed_by(Action::Pause).is_some() {
+        actions.consume(Action::Pause);
+        state.pop().unwrap();
+    }
+}
+
+fn game_over_event_system(
+    mut events: EventReader<GameOverEvent>,
+    mut state: ResMut<State<AppState>>,
+    mut sim_time: ResMut<SimTime>,
+) {
+    if events.iter().next().is_some() {
+        sim_time.halt();
+        state.set(AppState::GameOver).unwrap();
+    }
+}
+
+fn game_over_input_system(
+    mut actions: ResMut<ActionState>,
+    mut state: ResMut<State<AppState>>,
+    pending: Res<PendingInitials>,
+) {
+    //イニシャル入力中はタイトルに戻らない
+    if pending.0.is_none() && actions.just_pressed_by(Action::Fire).is_some() {
+        actions.consume(Action::Fire);
+        state.set(AppState::Title).unwrap();
+    }
+}
+
+type GameplayEntityFilter = Or<(
+    With<Laser>,
+    With<Enemy>,
+    With<Boss>,
+    With<BossPart>,
+    With<Explosion>,
+    With<ExplosionToSpawn>,
+    With<ChainExplosion>,
+    With<Pickup>,
+    With<Player>,
+)>;
+
+//ゲーム中のエンティティを全て削除する
+fn gameplay_cleanup_system(
+    mut commands: Commands,
+    query: Query<Entity, GameplayEntityFilter>,
+) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn();
+    }
+}
+
+//ゲーム開始時にリソースを初期化する
+fn gameplay_reset_system(
+    mut enemy_count: ResMut<EnemyCount>,
+    mut players: ResMut<Players>,
+    player_count: Res<PlayerCount>,
+    mut formation_maker: ResMut<FormationMaker>,
+    mut sim_time: ResMut<SimTime>,
+    config: Res<GameConfig>,
+) {
+    sim_time.reset();
+    enemy_count.0 = 0;
+    *players = Players::new(player_count.0, config.player.lives, config.player.bombs);
+    *formation_maker = FormationMaker::default();
+}